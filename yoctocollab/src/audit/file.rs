@@ -0,0 +1,114 @@
+use std::{
+    fmt::Write as _,
+    fs::{File, OpenOptions},
+    io::Write,
+    path::Path,
+    sync::{Arc, Mutex},
+    time::UNIX_EPOCH,
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use tokio::task::spawn_blocking;
+
+use super::{AuditRecord, AuditSink, Error};
+
+/// Appends every record to a file as a line of JSON, e.g.
+///
+/// ```json
+/// {"timestamp":1718000000000,"document":"a","connection_id":1,"user_id":"u","size":24,"root_types":["content"]}
+/// ```
+///
+/// the timestamp counts milliseconds since the unix epoch.
+#[derive(Clone)]
+pub struct FileAuditSink {
+    file: Arc<Mutex<File>>,
+}
+
+impl FileAuditSink {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| {
+                Error::Io(format!(
+                    "open audit log `{}` failed, err: {err}",
+                    path.display()
+                ))
+            })?;
+
+        Ok(Self {
+            file: Arc::new(Mutex::new(file)),
+        })
+    }
+
+    async fn record_inline(&self, line: String) -> Result<(), Error> {
+        let file = self.file.clone();
+
+        spawn_blocking(move || {
+            let mut file = file
+                .lock()
+                .map_err(|err| Error::Backend(format!("audit log poisoned, err: {err}")))?;
+
+            file.write_all(line.as_bytes())
+                .map_err(|err| Error::Io(format!("write audit log failed, err: {err}")))
+        })
+        .await
+        .map_err(|err| Error::Backend(format!("audit log task failed, err: {err}")))?
+    }
+}
+
+impl AuditSink for FileAuditSink {
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, Result<(), Error>> {
+        self.record_inline(encode_record(record)).boxed()
+    }
+}
+
+fn encode_record(record: &AuditRecord) -> String {
+    let timestamp = record
+        .timestamp
+        .duration_since(UNIX_EPOCH)
+        .map(|timestamp| timestamp.as_millis())
+        .unwrap_or_default();
+
+    let mut line = format!("{{\"timestamp\":{timestamp},\"document\":");
+    write_json_string(&mut line, &record.document_name);
+    let _ = write!(
+        line,
+        ",\"connection_id\":{},\"user_id\":",
+        record.connection_id
+    );
+    match &record.user_id {
+        Some(user_id) => write_json_string(&mut line, user_id),
+        None => line.push_str("null"),
+    }
+    let _ = write!(line, ",\"size\":{},\"root_types\":[", record.size);
+    for (i, root_type) in record.root_types.iter().enumerate() {
+        if i > 0 {
+            line.push(',');
+        }
+        write_json_string(&mut line, root_type);
+    }
+    line.push_str("]}\n");
+
+    line
+}
+
+fn write_json_string(buffer: &mut String, value: &str) {
+    buffer.push('"');
+    for c in value.chars() {
+        match c {
+            '"' => buffer.push_str("\\\""),
+            '\\' => buffer.push_str("\\\\"),
+            '\n' => buffer.push_str("\\n"),
+            '\r' => buffer.push_str("\\r"),
+            '\t' => buffer.push_str("\\t"),
+            c if c.is_control() => {
+                let _ = write!(buffer, "\\u{:04x}", c as u32);
+            }
+            c => buffer.push(c),
+        }
+    }
+    buffer.push('"');
+}
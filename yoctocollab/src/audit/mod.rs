@@ -0,0 +1,60 @@
+mod file;
+mod roots;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::{fmt, time::SystemTime};
+
+use futures::{
+    future::{ready, BoxFuture},
+    FutureExt,
+};
+
+pub use file::FileAuditSink;
+pub(crate) use roots::RootIndex;
+#[cfg(feature = "sqlite")]
+pub use sqlite::SqliteAuditSink;
+
+#[derive(Debug)]
+pub enum Error {
+    Io(String),
+    Backend(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(msg) | Self::Backend(msg) => f.write_str(msg),
+        }
+    }
+}
+
+/// An update applied to a document.
+#[derive(Debug, Clone)]
+pub struct AuditRecord {
+    pub document_name: String,
+    pub connection_id: u64,
+    /// the user of the authenticated connection
+    pub user_id: Option<String>,
+    pub timestamp: SystemTime,
+    /// bytes of the v1 update
+    pub size: usize,
+    /// the root types whose content the update inserts or deletes
+    pub root_types: Vec<String>,
+}
+
+/// Records every update applied to a document, a closure taking an
+/// [`AuditRecord`] is a sink as well.
+pub trait AuditSink: Send + Sync + 'static {
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, Result<(), Error>>;
+}
+
+impl<F> AuditSink for F
+where
+    F: Fn(&AuditRecord) + Send + Sync + 'static,
+{
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, Result<(), Error>> {
+        self(record);
+        ready(Ok(())).boxed()
+    }
+}
@@ -0,0 +1,152 @@
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    ops::Range,
+    sync::Arc,
+};
+
+use y_octo::{Id, JwstCodecResult};
+
+use crate::protocol::{read_update_structs, StructParent, UpdateStruct};
+
+/// Maps the clocks of every client to the root type holding their structs,
+/// an update only names the root type of items inserted without origins.
+#[derive(Default)]
+pub(crate) struct RootIndex {
+    /// the structs of each client by their first clock, with the clock
+    /// after them and their root type
+    clients: HashMap<u64, BTreeMap<u64, (u64, Arc<str>)>>,
+    /// the deleted clocks of each client, updates repeat the whole delete
+    /// set of their document
+    deleted: HashMap<u64, BTreeMap<u64, u64>>,
+}
+
+impl RootIndex {
+    /// Indexes the structs of the update, returns the root types whose
+    /// content it inserts or deletes.
+    pub(crate) fn apply(&mut self, update: &[u8]) -> JwstCodecResult<Vec<String>> {
+        let structs = read_update_structs(update)?;
+        let mut root_types = BTreeSet::new();
+
+        // structs may refer to structs after them in the same update
+        let mut pending = structs
+            .structs
+            .into_iter()
+            .filter(|update_struct| {
+                !matches!(update_struct.parent, StructParent::None)
+                    && update_struct.len > 0
+                    && self
+                        .resolve(&Id::new(
+                            update_struct.id.client,
+                            update_struct.id.clock + update_struct.len - 1,
+                        ))
+                        .is_none()
+            })
+            .collect::<Vec<_>>();
+        loop {
+            let before = pending.len();
+            pending.retain(|update_struct| match self.resolve_parent(update_struct) {
+                Some(root) => {
+                    root_types.insert(root.to_string());
+                    self.insert(update_struct, root);
+                    false
+                }
+                None => true,
+            });
+            if pending.is_empty() || pending.len() == before {
+                break;
+            }
+        }
+
+        for (client, range) in structs.deletes {
+            for range in self.delete(client, range) {
+                let Some(index) = self.clients.get(&client) else {
+                    continue;
+                };
+                let first = index
+                    .range(..=range.start)
+                    .next_back()
+                    .map_or(range.start, |(clock, _)| *clock);
+                root_types.extend(
+                    index
+                        .range(first..range.end)
+                        .filter(|(_, (end, _))| *end > range.start)
+                        .map(|(_, (_, root))| root.to_string()),
+                );
+            }
+        }
+
+        Ok(root_types.into_iter().collect())
+    }
+
+    /// Marks the clocks deleted, returns the ranges which were not deleted
+    /// before.
+    fn delete(&mut self, client: u64, range: Range<u64>) -> Vec<Range<u64>> {
+        let deleted = self.deleted.entry(client).or_default();
+
+        let mut start = range.start;
+        let mut end = range.end;
+        let mut fresh = Vec::new();
+        let mut cursor = range.start;
+
+        let first = deleted
+            .range(..=range.start)
+            .next_back()
+            .map_or(range.start, |(clock, _)| *clock);
+        let overlaps = deleted
+            .range(first..=range.end)
+            .filter(|(_, prev_end)| **prev_end >= range.start)
+            .map(|(prev_start, prev_end)| (*prev_start, *prev_end))
+            .collect::<Vec<_>>();
+        for (prev_start, prev_end) in overlaps {
+            if prev_start > cursor {
+                fresh.push(cursor..prev_start.min(range.end));
+            }
+            cursor = cursor.max(prev_end);
+            start = start.min(prev_start);
+            end = end.max(prev_end);
+            deleted.remove(&prev_start);
+        }
+        if cursor < range.end {
+            fresh.push(cursor..range.end);
+        }
+
+        // adjacent and overlapping ranges are merged
+        deleted.insert(start, end);
+
+        fresh
+    }
+
+    fn resolve(&self, id: &Id) -> Option<&Arc<str>> {
+        let (_, (end, root)) = self
+            .clients
+            .get(&id.client)?
+            .range(..=id.clock)
+            .next_back()?;
+
+        (id.clock < *end).then_some(root)
+    }
+
+    fn resolve_parent(&self, update_struct: &UpdateStruct) -> Option<Arc<str>> {
+        match &update_struct.parent {
+            StructParent::Root(name) => Some(name.as_str().into()),
+            StructParent::Item(id) => self.resolve(id).cloned(),
+            StructParent::None => None,
+        }
+    }
+
+    fn insert(&mut self, update_struct: &UpdateStruct, root: Arc<str>) {
+        let index = self.clients.entry(update_struct.id.client).or_default();
+        let start = update_struct.id.clock;
+        let end = start + update_struct.len;
+
+        // consecutive structs of the same root type share one entry
+        if let Some((_, (prev_end, prev_root))) = index.range_mut(..start).next_back() {
+            if *prev_end == start && *prev_root == root {
+                *prev_end = end;
+                return;
+            }
+        }
+
+        index.insert(start, (end, root));
+    }
+}
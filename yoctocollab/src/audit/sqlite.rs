@@ -0,0 +1,139 @@
+use std::{
+    collections::HashMap,
+    path::Path,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use rusqlite::{params, Connection};
+
+use crate::utils::SqliteConnection;
+
+use super::{AuditRecord, AuditSink, Error};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS audit_log (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    connection_id INTEGER NOT NULL,
+    user_id TEXT,
+    created_at INTEGER NOT NULL,
+    size INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_log_name ON audit_log (name, id);
+CREATE TABLE IF NOT EXISTS audit_root_types (
+    record_id INTEGER NOT NULL REFERENCES audit_log (id),
+    root_type TEXT NOT NULL
+);
+CREATE INDEX IF NOT EXISTS audit_root_types_record ON audit_root_types (record_id);
+";
+
+/// Stores records in the `audit_log` table of a SQLite database with their
+/// root types in the `audit_root_types` table, `created_at` counts
+/// milliseconds since the unix epoch.
+#[derive(Clone)]
+pub struct SqliteAuditSink {
+    conn: SqliteConnection,
+}
+
+impl SqliteAuditSink {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        SqliteConnection::open("sqlite audit sink", path, SCHEMA)
+            .map(|conn| Self { conn })
+            .map_err(Error::Backend)
+    }
+
+    pub fn open_in_memory() -> Result<Self, Error> {
+        SqliteConnection::open_in_memory("sqlite audit sink", SCHEMA)
+            .map(|conn| Self { conn })
+            .map_err(Error::Backend)
+    }
+
+    /// The records of the document in the order they were applied.
+    pub async fn records(&self, name: &str) -> Result<Vec<AuditRecord>, Error> {
+        let name = name.to_owned();
+
+        self.blocking(move |conn| {
+            let mut root_types = HashMap::<i64, Vec<String>>::new();
+            let mut stmt = conn.prepare(
+                "SELECT audit_root_types.record_id, audit_root_types.root_type
+                 FROM audit_root_types JOIN audit_log ON audit_log.id = audit_root_types.record_id
+                 WHERE audit_log.name = ?1 ORDER BY audit_root_types.rowid",
+            )?;
+            for row in stmt.query_map(params![name], |row| Ok((row.get(0)?, row.get(1)?)))? {
+                let (record_id, root_type) = row?;
+                root_types.entry(record_id).or_default().push(root_type);
+            }
+
+            let mut stmt = conn.prepare(
+                "SELECT id, connection_id, user_id, created_at, size
+                 FROM audit_log WHERE name = ?1 ORDER BY id",
+            )?;
+            let records = stmt.query_map(params![name], |row| {
+                let id: i64 = row.get(0)?;
+                let connection_id: i64 = row.get(1)?;
+                let created_at: i64 = row.get(3)?;
+                let size: i64 = row.get(4)?;
+
+                Ok(AuditRecord {
+                    document_name: name.clone(),
+                    connection_id: connection_id as u64,
+                    user_id: row.get(2)?,
+                    timestamp: UNIX_EPOCH + Duration::from_millis(created_at as u64),
+                    size: size as usize,
+                    root_types: root_types.remove(&id).unwrap_or_default(),
+                })
+            })?;
+
+            records.collect()
+        })
+        .await
+    }
+
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, Error> {
+        self.conn.blocking(f).await.map_err(Error::Backend)
+    }
+
+    async fn record_inline(&self, record: AuditRecord) -> Result<(), Error> {
+        let created_at = record
+            .timestamp
+            .duration_since(UNIX_EPOCH)
+            .map(|timestamp| timestamp.as_millis() as i64)
+            .unwrap_or_default();
+
+        self.blocking(move |conn| {
+            let tx = conn.transaction()?;
+
+            tx.execute(
+                "INSERT INTO audit_log (name, connection_id, user_id, created_at, size)
+                 VALUES (?1, ?2, ?3, ?4, ?5)",
+                params![
+                    record.document_name,
+                    record.connection_id as i64,
+                    record.user_id,
+                    created_at,
+                    record.size as i64,
+                ],
+            )?;
+            let record_id = tx.last_insert_rowid();
+            for root_type in &record.root_types {
+                tx.execute(
+                    "INSERT INTO audit_root_types (record_id, root_type) VALUES (?1, ?2)",
+                    params![record_id, root_type],
+                )?;
+            }
+
+            tx.commit()
+        })
+        .await
+    }
+}
+
+impl AuditSink for SqliteAuditSink {
+    fn record<'a>(&'a self, record: &'a AuditRecord) -> BoxFuture<'a, Result<(), Error>> {
+        self.record_inline(record.clone()).boxed()
+    }
+}
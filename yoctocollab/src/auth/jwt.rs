@@ -0,0 +1,298 @@
+use std::{
+    collections::HashMap,
+    fmt,
+    time::{Duration, UNIX_EPOCH},
+};
+
+use futures::{
+    future::{ready, BoxFuture},
+    FutureExt,
+};
+use jsonwebtoken::{decode, decode_header, errors::ErrorKind, Algorithm, DecodingKey, Validation};
+use serde::Deserialize;
+
+use super::{AuthRequest, Authentication, Authenticator, Permission};
+use crate::extension::Reject;
+
+const WILDCARD_DOCUMENT: &str = "*";
+
+#[derive(Debug)]
+pub enum Error {
+    InvalidKey(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::InvalidKey(msg) => f.write_str(msg),
+        }
+    }
+}
+
+#[derive(Deserialize)]
+struct Claims {
+    sub: Option<String>,
+    exp: u64,
+    /// the permission granted per document name, `*` matches every document
+    documents: Option<HashMap<String, String>>,
+}
+
+/// Verifies JWTs signed with HS256, RS256 or EdDSA.
+///
+/// `exp` is required and checked together with `nbf`, `aud` is checked once
+/// an audience is configured. `sub` becomes the user id, and a `documents`
+/// claim restricts the token to the documents it names, with the permission
+/// (`read`, `write` or `admin`) of each:
+///
+/// ```json
+/// { "sub": "alice", "exp": 1718000000, "documents": { "notes/a": "read", "*": "write" } }
+/// ```
+///
+/// Without a `documents` claim the token may write every document.
+#[derive(Default)]
+pub struct JwtAuthenticator {
+    keys: Vec<(Algorithm, DecodingKey)>,
+    audience: Vec<String>,
+    leeway: Duration,
+}
+
+impl JwtAuthenticator {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn hs256_secret(mut self, secret: &[u8]) -> Self {
+        self.keys
+            .push((Algorithm::HS256, DecodingKey::from_secret(secret)));
+        self
+    }
+
+    pub fn rs256_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        let key = DecodingKey::from_rsa_pem(pem)
+            .map_err(|err| Error::InvalidKey(format!("invalid rsa pem, err: {err}")))?;
+        self.keys.push((Algorithm::RS256, key));
+        Ok(self)
+    }
+
+    pub fn eddsa_pem(mut self, pem: &[u8]) -> Result<Self, Error> {
+        let key = DecodingKey::from_ed_pem(pem)
+            .map_err(|err| Error::InvalidKey(format!("invalid ed25519 pem, err: {err}")))?;
+        self.keys.push((Algorithm::EdDSA, key));
+        Ok(self)
+    }
+
+    /// Accepts tokens issued for the audience, may be called repeatedly.
+    pub fn audience<S: Into<String>>(mut self, audience: S) -> Self {
+        self.audience.push(audience.into());
+        self
+    }
+
+    /// Tolerates clock skew when checking `exp` and `nbf`.
+    pub fn leeway(mut self, leeway: Duration) -> Self {
+        self.leeway = leeway;
+        self
+    }
+
+    fn verify(&self, token: &str, document_name: &str) -> Result<Authentication, Reject> {
+        let header = decode_header(token).map_err(|_| Reject::new("invalid_token"))?;
+
+        let mut validation = Validation::new(header.alg);
+        validation.validate_nbf = true;
+        validation.leeway = self.leeway.as_secs();
+        if self.audience.is_empty() {
+            validation.validate_aud = false;
+        } else {
+            validation.set_audience(&self.audience);
+        }
+
+        // tries every key of the algorithm, so that keys can be rotated
+        let mut claims = None;
+        for (_, key) in self.keys.iter().filter(|(alg, _)| *alg == header.alg) {
+            match decode::<Claims>(token, key, &validation) {
+                Ok(data) => {
+                    claims = Some(data.claims);
+                    break;
+                }
+                Err(err) if matches!(err.kind(), ErrorKind::InvalidSignature) => continue,
+                Err(err) => return Err(reject(err.kind())),
+            }
+        }
+        let claims = claims.ok_or_else(|| Reject::new("invalid_token"))?;
+
+        let permission = match &claims.documents {
+            Some(documents) => documents
+                .get(document_name)
+                .or_else(|| documents.get(WILDCARD_DOCUMENT))
+                .ok_or_else(|| Reject::new("forbidden"))?
+                .parse::<Permission>()
+                .map_err(|err| {
+                    log::warn!("invalid documents claim, err: {err}");
+                    Reject::new("invalid_token")
+                })?,
+            None => Permission::default(),
+        };
+
+        Ok(Authentication {
+            user_id: claims.sub,
+            permission,
+            expires_at: Some(UNIX_EPOCH + Duration::from_secs(claims.exp)),
+        })
+    }
+}
+
+impl Authenticator for JwtAuthenticator {
+    fn authenticate<'a>(
+        &'a self,
+        request: AuthRequest<'a>,
+    ) -> BoxFuture<'a, Result<Authentication, Reject>> {
+        ready(self.verify(request.token, request.document_name)).boxed()
+    }
+
+    fn authenticate_handshake(
+        &self,
+        request: AuthRequest<'_>,
+    ) -> Option<Result<Authentication, Reject>> {
+        Some(self.verify(request.token, request.document_name))
+    }
+}
+
+fn reject(kind: &ErrorKind) -> Reject {
+    match kind {
+        ErrorKind::ExpiredSignature => Reject::new("token_expired"),
+        ErrorKind::ImmatureSignature => Reject::new("token_not_yet_valid"),
+        ErrorKind::InvalidAudience => Reject::new("invalid_audience"),
+        _ => Reject::new("invalid_token"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::SystemTime;
+
+    use jsonwebtoken::{encode, EncodingKey, Header};
+    use serde::Serialize;
+
+    use super::*;
+
+    const SECRET: &[u8] = b"secret";
+
+    #[derive(Serialize, Default)]
+    struct TestClaims {
+        sub: Option<String>,
+        exp: u64,
+        aud: Option<String>,
+        documents: Option<HashMap<String, String>>,
+    }
+
+    /// Claims expiring `expires_in` seconds from now, negative for the past.
+    fn claims(expires_in: i64) -> TestClaims {
+        let now = SystemTime::now().duration_since(UNIX_EPOCH).unwrap();
+
+        TestClaims {
+            sub: Some("alice".into()),
+            exp: now.as_secs().saturating_add_signed(expires_in),
+            ..Default::default()
+        }
+    }
+
+    fn token(alg: Algorithm, claims: &TestClaims) -> String {
+        encode(&Header::new(alg), claims, &EncodingKey::from_secret(SECRET)).unwrap()
+    }
+
+    fn reason(result: Result<Authentication, Reject>) -> String {
+        result.unwrap_err().reason().to_owned()
+    }
+
+    #[test]
+    fn verify_expired() {
+        let authenticator = JwtAuthenticator::new().hs256_secret(SECRET);
+
+        let authentication = authenticator
+            .verify(&token(Algorithm::HS256, &claims(100)), "doc")
+            .unwrap();
+        assert_eq!(authentication.user_id.as_deref(), Some("alice"));
+        assert_eq!(authentication.permission, Permission::Write);
+
+        let expired = token(Algorithm::HS256, &claims(-100));
+        assert_eq!(
+            reason(authenticator.verify(&expired, "doc")),
+            "token_expired"
+        );
+        let authenticator = authenticator.leeway(Duration::from_secs(200));
+        assert!(authenticator.verify(&expired, "doc").is_ok());
+    }
+
+    #[test]
+    fn verify_audience() {
+        let authenticator = JwtAuthenticator::new()
+            .hs256_secret(SECRET)
+            .audience("editor");
+
+        let mut claims = claims(100);
+        claims.aud = Some("editor".into());
+        assert!(authenticator
+            .verify(&token(Algorithm::HS256, &claims), "doc")
+            .is_ok());
+
+        claims.aud = Some("viewer".into());
+        assert_eq!(
+            reason(authenticator.verify(&token(Algorithm::HS256, &claims), "doc")),
+            "invalid_audience"
+        );
+    }
+
+    #[test]
+    fn verify_documents() {
+        let authenticator = JwtAuthenticator::new().hs256_secret(SECRET);
+
+        let mut claims = claims(100);
+        claims.documents = Some(HashMap::from([("notes/a".into(), "read".into())]));
+        let restricted = token(Algorithm::HS256, &claims);
+        assert_eq!(
+            authenticator
+                .verify(&restricted, "notes/a")
+                .unwrap()
+                .permission,
+            Permission::Read
+        );
+        assert_eq!(
+            reason(authenticator.verify(&restricted, "notes/b")),
+            "forbidden"
+        );
+
+        claims
+            .documents
+            .as_mut()
+            .unwrap()
+            .insert(WILDCARD_DOCUMENT.into(), "admin".into());
+        let wildcard = token(Algorithm::HS256, &claims);
+        assert_eq!(
+            authenticator
+                .verify(&wildcard, "notes/a")
+                .unwrap()
+                .permission,
+            Permission::Read
+        );
+        assert_eq!(
+            authenticator
+                .verify(&wildcard, "notes/b")
+                .unwrap()
+                .permission,
+            Permission::Admin
+        );
+    }
+
+    #[test]
+    fn verify_algorithm_without_key() {
+        let authenticator = JwtAuthenticator::new().hs256_secret(SECRET);
+
+        // signed with the same secret, but no key is registered for HS384
+        let hs384 = token(Algorithm::HS384, &claims(100));
+        assert_eq!(reason(authenticator.verify(&hs384, "doc")), "invalid_token");
+
+        // a key of the algorithm, but not the one which signed the token
+        let other = JwtAuthenticator::new().hs256_secret(b"other");
+        let hs256 = token(Algorithm::HS256, &claims(100));
+        assert_eq!(reason(other.verify(&hs256, "doc")), "invalid_token");
+    }
+}
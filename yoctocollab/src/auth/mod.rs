@@ -0,0 +1,115 @@
+#[cfg(feature = "jwt")]
+mod jwt;
+
+use std::{str::FromStr, time::SystemTime};
+
+use futures::future::BoxFuture;
+use tokio_tungstenite::tungstenite::http::header::AUTHORIZATION;
+
+use crate::{
+    extension::{Reject, RequestInfo},
+    route,
+};
+
+#[cfg(feature = "jwt")]
+pub use jwt::{Error, JwtAuthenticator};
+
+const TOKEN_QUERY_KEY: &str = "token";
+const BEARER_PREFIX: &str = "Bearer ";
+
+#[derive(Clone, Copy)]
+pub struct AuthRequest<'a> {
+    pub connection_id: u64,
+    pub document_name: &'a str,
+    /// the token sent by the client, empty when the client has none
+    pub token: &'a str,
+    pub request: &'a RequestInfo,
+}
+
+/// What a connection may do with a document, each level includes the ones
+/// below it.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, PartialOrd, Ord)]
+pub enum Permission {
+    /// Syncs the document and shares awareness, updates are denied.
+    Read,
+    /// Updates the document.
+    #[default]
+    Write,
+    /// Administers the document.
+    Admin,
+}
+
+impl Permission {
+    /// The scope replied to the client in the `Authenticated` message.
+    pub(crate) const fn scope(self) -> &'static str {
+        match self {
+            Self::Read => "readonly",
+            Self::Write | Self::Admin => "read-write",
+        }
+    }
+}
+
+impl FromStr for Permission {
+    type Err = String;
+
+    fn from_str(value: &str) -> Result<Self, Self::Err> {
+        match value {
+            "read" | "readonly" => Ok(Self::Read),
+            "write" | "read-write" => Ok(Self::Write),
+            "admin" => Ok(Self::Admin),
+            _ => Err(format!("unknown permission `{value}`")),
+        }
+    }
+}
+
+/// Who opened the document and what it may do with it.
+#[derive(Debug, Clone, Default)]
+pub struct Authentication {
+    pub user_id: Option<String>,
+    pub permission: Permission,
+    /// when the token expires, the client is asked for a new token shortly
+    /// before and disconnected once it has expired
+    pub expires_at: Option<SystemTime>,
+}
+
+/// Decides whether a connection may open a document.
+///
+/// Once an authenticator is registered, a connection has to send an `Auth`
+/// message for every document before anything else, messages of documents
+/// not yet authenticated are dropped. A token carried by the handshake
+/// request authenticates the document named in its url right away.
+pub trait Authenticator: Send + Sync + 'static {
+    fn authenticate<'a>(
+        &'a self,
+        request: AuthRequest<'a>,
+    ) -> BoxFuture<'a, Result<Authentication, Reject>>;
+
+    /// Checks the token of the handshake request before the websocket is
+    /// accepted, a rejected token fails the handshake with `401
+    /// Unauthorized`. `None` leaves the token to `authenticate` once the
+    /// websocket is open, for authenticators which cannot decide right away.
+    fn authenticate_handshake(
+        &self,
+        request: AuthRequest<'_>,
+    ) -> Option<Result<Authentication, Reject>> {
+        let _ = request;
+        None
+    }
+}
+
+/// Reads the token of the handshake request, from a bearer `Authorization`
+/// header or else from the `token` query parameter.
+pub(crate) fn request_token(request: &RequestInfo) -> Option<String> {
+    let bearer = request
+        .headers
+        .get(AUTHORIZATION)
+        .and_then(|value| value.to_str().ok())
+        .and_then(|value| value.strip_prefix(BEARER_PREFIX));
+    if let Some(token) = bearer {
+        return Some(token.trim().to_owned());
+    }
+
+    route::query_param(&request.uri, TOKEN_QUERY_KEY)
+        .ok()
+        .flatten()
+}
@@ -0,0 +1,473 @@
+use std::{
+    env, fmt, fs,
+    net::{Ipv4Addr, SocketAddr, SocketAddrV4, ToSocketAddrs},
+    path::Path,
+    str::FromStr,
+    time::Duration,
+};
+
+use serde::Deserialize;
+use tokio_tungstenite::tungstenite::protocol::WebSocketConfig;
+
+use crate::utils::Snowflake;
+
+const DEFAULT_BIND: SocketAddr = SocketAddr::V4(SocketAddrV4::new(Ipv4Addr::LOCALHOST, 2976));
+const ENV_PREFIX: &str = "YOCTOCOLLAB_";
+
+#[derive(Debug)]
+pub enum Error {
+    Io(String),
+    Parse(String),
+    Invalid(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(msg) | Self::Parse(msg) | Self::Invalid(msg) => f.write_str(msg),
+        }
+    }
+}
+
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_DEBOUNCE: Duration = Duration::from_secs(2);
+const DEFAULT_MAX_DEBOUNCE: Duration = Duration::from_secs(10);
+const DEFAULT_AWARENESS_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// Limits and timeouts applied to every room.
+#[derive(Debug, Clone)]
+pub struct RoomConfig {
+    /// How long an empty room is kept loaded before it is unloaded, a page
+    /// reload within it finds the document in memory.
+    pub(crate) idle_timeout: Duration,
+    /// How long an unloaded room stays registered with its document in the
+    /// store before it is destroyed, zero destroys it right away.
+    pub(crate) hibernate_timeout: Duration,
+    pub(crate) max_connections: Option<usize>,
+
+    /// How long the room waits for further updates before storing them.
+    pub(crate) debounce: Duration,
+    /// The longest time an update may stay unstored while updates keep coming.
+    pub(crate) max_debounce: Duration,
+
+    /// How long an awareness state lives without its clock advancing, zero
+    /// keeps states until their connection leaves.
+    pub(crate) awareness_timeout: Duration,
+
+    /// Binds the Yjs client ids of document updates to the connection which
+    /// authored them first, updates using a client id bound to another
+    /// connection are denied.
+    pub(crate) bind_client_ids: bool,
+
+    /// Sends the full document to read-only connections as they join, viewers
+    /// get the content without starting the sync themselves.
+    pub(crate) push_state: bool,
+}
+
+impl Default for RoomConfig {
+    fn default() -> Self {
+        Self {
+            idle_timeout: DEFAULT_IDLE_TIMEOUT,
+            hibernate_timeout: Duration::ZERO,
+            max_connections: None,
+
+            debounce: DEFAULT_DEBOUNCE,
+            max_debounce: DEFAULT_MAX_DEBOUNCE,
+
+            awareness_timeout: DEFAULT_AWARENESS_TIMEOUT,
+
+            bind_client_ids: false,
+
+            push_state: false,
+        }
+    }
+}
+
+const DEFAULT_REFRESH_MARGIN: Duration = Duration::from_secs(60);
+
+/// Authentication applied to every connection.
+#[derive(Debug, Clone)]
+pub struct AuthConfig {
+    /// How long before its token expires a document asks the client for a
+    /// new token.
+    pub(crate) refresh_margin: Duration,
+}
+
+impl Default for AuthConfig {
+    fn default() -> Self {
+        Self {
+            refresh_margin: DEFAULT_REFRESH_MARGIN,
+        }
+    }
+}
+
+#[derive(Debug, Clone)]
+pub struct ServerConfig {
+    pub(crate) bind: Vec<SocketAddr>,
+    /// Listeners speaking the y-websocket framing, the document of a
+    /// connection is named by its URL.
+    pub(crate) y_websocket_bind: Vec<SocketAddr>,
+    pub(crate) machine_id: u64,
+
+    pub(crate) max_message_size: Option<usize>,
+    pub(crate) max_frame_size: Option<usize>,
+
+    pub(crate) room: RoomConfig,
+    pub(crate) auth: AuthConfig,
+}
+
+/// Listens on `127.0.0.1:2976`, like a config built without any address.
+impl Default for ServerConfig {
+    fn default() -> Self {
+        Self {
+            bind: vec![DEFAULT_BIND],
+            y_websocket_bind: Vec::new(),
+            machine_id: 0,
+
+            max_message_size: None,
+            max_frame_size: None,
+
+            room: RoomConfig::default(),
+            auth: AuthConfig::default(),
+        }
+    }
+}
+
+impl ServerConfig {
+    pub fn builder() -> ServerConfigBuilder {
+        ServerConfigBuilder::default()
+    }
+
+    pub(crate) fn websocket_config(&self) -> WebSocketConfig {
+        let default = WebSocketConfig::default();
+
+        WebSocketConfig {
+            max_message_size: self.max_message_size.or(default.max_message_size),
+            max_frame_size: self.max_frame_size.or(default.max_frame_size),
+            ..default
+        }
+    }
+}
+
+/// Builds a [`ServerConfig`], values can be layered from a TOML file, the
+/// `YOCTOCOLLAB_*` environment variables and explicit setters, the last one
+/// applied wins. Durations are in seconds, unless the key ends with `_ms`.
+///
+/// ```toml
+/// bind = ["0.0.0.0:2976"]
+/// y_websocket_bind = ["0.0.0.0:1234"]
+/// machine_id = 1
+///
+/// [websocket]
+/// max_message_size = 67108864
+/// max_frame_size = 16777216
+///
+/// [room]
+/// idle_timeout = 30
+/// hibernate_timeout = 600
+/// max_connections = 128
+/// debounce_ms = 2000
+/// max_debounce_ms = 10000
+/// awareness_timeout = 30
+/// bind_client_ids = false
+/// push_state = false
+///
+/// [auth]
+/// refresh_margin = 60
+/// ```
+#[derive(Debug)]
+pub struct ServerConfigBuilder {
+    config: ServerConfig,
+}
+
+/// Starts without any address, `build` falls back to the default one when
+/// none is configured.
+impl Default for ServerConfigBuilder {
+    fn default() -> Self {
+        Self {
+            config: ServerConfig {
+                bind: Vec::new(),
+                ..ServerConfig::default()
+            },
+        }
+    }
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawServerConfig {
+    bind: Option<Vec<String>>,
+    y_websocket_bind: Option<Vec<String>>,
+    machine_id: Option<u64>,
+    websocket: RawWebSocketConfig,
+    room: RawRoomConfig,
+    auth: RawAuthConfig,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawWebSocketConfig {
+    max_message_size: Option<usize>,
+    max_frame_size: Option<usize>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawRoomConfig {
+    idle_timeout: Option<u64>,
+    hibernate_timeout: Option<u64>,
+    max_connections: Option<usize>,
+    debounce_ms: Option<u64>,
+    max_debounce_ms: Option<u64>,
+    awareness_timeout: Option<u64>,
+    bind_client_ids: Option<bool>,
+    push_state: Option<bool>,
+}
+
+#[derive(Deserialize, Default)]
+#[serde(default, deny_unknown_fields)]
+struct RawAuthConfig {
+    refresh_margin: Option<u64>,
+}
+
+impl ServerConfigBuilder {
+    pub fn from_toml_file<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        let path = path.as_ref();
+        let content = fs::read_to_string(path).map_err(|err| {
+            Error::Io(format!(
+                "read config `{}` failed, err: {err}",
+                path.display()
+            ))
+        })?;
+
+        Self::from_toml_str(&content)
+    }
+
+    pub fn from_toml_str(content: &str) -> Result<Self, Error> {
+        let raw: RawServerConfig = toml::from_str(content)
+            .map_err(|err| Error::Parse(format!("parse config failed, err: {err}")))?;
+
+        Self::default().merge(raw)
+    }
+
+    /// Overrides the values with the `YOCTOCOLLAB_*` environment variables,
+    /// e.g. `YOCTOCOLLAB_BIND=0.0.0.0:2976,[::]:2976` or
+    /// `YOCTOCOLLAB_ROOM_IDLE_TIMEOUT=30`.
+    pub fn env(self) -> Result<Self, Error> {
+        let raw = RawServerConfig {
+            bind: read_env("BIND")?.map(|bind: String| split_addrs(&bind)),
+            y_websocket_bind: read_env("Y_WEBSOCKET_BIND")?.map(|bind: String| split_addrs(&bind)),
+            machine_id: read_env("MACHINE_ID")?,
+            websocket: RawWebSocketConfig {
+                max_message_size: read_env("WEBSOCKET_MAX_MESSAGE_SIZE")?,
+                max_frame_size: read_env("WEBSOCKET_MAX_FRAME_SIZE")?,
+            },
+            room: RawRoomConfig {
+                idle_timeout: read_env("ROOM_IDLE_TIMEOUT")?,
+                hibernate_timeout: read_env("ROOM_HIBERNATE_TIMEOUT")?,
+                max_connections: read_env("ROOM_MAX_CONNECTIONS")?,
+                debounce_ms: read_env("ROOM_DEBOUNCE_MS")?,
+                max_debounce_ms: read_env("ROOM_MAX_DEBOUNCE_MS")?,
+                awareness_timeout: read_env("ROOM_AWARENESS_TIMEOUT")?,
+                bind_client_ids: read_env("ROOM_BIND_CLIENT_IDS")?,
+                push_state: read_env("ROOM_PUSH_STATE")?,
+            },
+            auth: RawAuthConfig {
+                refresh_margin: read_env("AUTH_REFRESH_MARGIN")?,
+            },
+        };
+
+        self.merge(raw)
+    }
+
+    pub fn bind(mut self, addr: SocketAddr) -> Self {
+        self.config.bind.push(addr);
+        self
+    }
+
+    pub fn y_websocket_bind(mut self, addr: SocketAddr) -> Self {
+        self.config.y_websocket_bind.push(addr);
+        self
+    }
+
+    pub fn machine_id(mut self, machine_id: u64) -> Self {
+        self.config.machine_id = machine_id;
+        self
+    }
+
+    pub fn max_message_size(mut self, max_message_size: usize) -> Self {
+        self.config.max_message_size = Some(max_message_size);
+        self
+    }
+
+    pub fn max_frame_size(mut self, max_frame_size: usize) -> Self {
+        self.config.max_frame_size = Some(max_frame_size);
+        self
+    }
+
+    pub fn room_idle_timeout(mut self, idle_timeout: Duration) -> Self {
+        self.config.room.idle_timeout = idle_timeout;
+        self
+    }
+
+    pub fn room_hibernate_timeout(mut self, hibernate_timeout: Duration) -> Self {
+        self.config.room.hibernate_timeout = hibernate_timeout;
+        self
+    }
+
+    pub fn room_max_connections(mut self, max_connections: usize) -> Self {
+        self.config.room.max_connections = Some(max_connections);
+        self
+    }
+
+    pub fn room_debounce(mut self, debounce: Duration) -> Self {
+        self.config.room.debounce = debounce;
+        self
+    }
+
+    pub fn room_max_debounce(mut self, max_debounce: Duration) -> Self {
+        self.config.room.max_debounce = max_debounce;
+        self
+    }
+
+    pub fn room_awareness_timeout(mut self, awareness_timeout: Duration) -> Self {
+        self.config.room.awareness_timeout = awareness_timeout;
+        self
+    }
+
+    pub fn room_bind_client_ids(mut self, bind_client_ids: bool) -> Self {
+        self.config.room.bind_client_ids = bind_client_ids;
+        self
+    }
+
+    pub fn room_push_state(mut self, push_state: bool) -> Self {
+        self.config.room.push_state = push_state;
+        self
+    }
+
+    pub fn auth_refresh_margin(mut self, refresh_margin: Duration) -> Self {
+        self.config.auth.refresh_margin = refresh_margin;
+        self
+    }
+
+    pub fn build(mut self) -> Result<ServerConfig, Error> {
+        if self.config.bind.is_empty() && self.config.y_websocket_bind.is_empty() {
+            self.config.bind.push(DEFAULT_BIND);
+        }
+
+        if let (Some(max_message_size), Some(max_frame_size)) =
+            (self.config.max_message_size, self.config.max_frame_size)
+        {
+            if max_frame_size > max_message_size {
+                return Err(Error::Invalid(format!(
+                    "`max_frame_size` must not exceed `max_message_size`, max_frame_size: {max_frame_size}, max_message_size: {max_message_size}"
+                )));
+            }
+        }
+
+        if self.config.room.debounce > self.config.room.max_debounce {
+            return Err(Error::Invalid(
+                "`room.debounce_ms` must not exceed `room.max_debounce_ms`".into(),
+            ));
+        }
+
+        Snowflake::new(self.config.machine_id).map_err(|err| Error::Invalid(err.to_string()))?;
+
+        if self.config.room.max_connections == Some(0) {
+            return Err(Error::Invalid(
+                "`room.max_connections` must be greater than 0".into(),
+            ));
+        }
+
+        Ok(self.config)
+    }
+
+    fn merge(mut self, raw: RawServerConfig) -> Result<Self, Error> {
+        if let Some(bind) = raw.bind {
+            self.config.bind = bind
+                .iter()
+                .map(|addr| resolve(addr))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(bind) = raw.y_websocket_bind {
+            self.config.y_websocket_bind = bind
+                .iter()
+                .map(|addr| resolve(addr))
+                .collect::<Result<_, _>>()?;
+        }
+        if let Some(machine_id) = raw.machine_id {
+            self.config.machine_id = machine_id;
+        }
+
+        if let Some(max_message_size) = raw.websocket.max_message_size {
+            self.config.max_message_size = Some(max_message_size);
+        }
+        if let Some(max_frame_size) = raw.websocket.max_frame_size {
+            self.config.max_frame_size = Some(max_frame_size);
+        }
+
+        if let Some(idle_timeout) = raw.room.idle_timeout {
+            self.config.room.idle_timeout = Duration::from_secs(idle_timeout);
+        }
+        if let Some(hibernate_timeout) = raw.room.hibernate_timeout {
+            self.config.room.hibernate_timeout = Duration::from_secs(hibernate_timeout);
+        }
+        if let Some(max_connections) = raw.room.max_connections {
+            self.config.room.max_connections = Some(max_connections);
+        }
+        if let Some(debounce) = raw.room.debounce_ms {
+            self.config.room.debounce = Duration::from_millis(debounce);
+        }
+        if let Some(max_debounce) = raw.room.max_debounce_ms {
+            self.config.room.max_debounce = Duration::from_millis(max_debounce);
+        }
+        if let Some(awareness_timeout) = raw.room.awareness_timeout {
+            self.config.room.awareness_timeout = Duration::from_secs(awareness_timeout);
+        }
+        if let Some(bind_client_ids) = raw.room.bind_client_ids {
+            self.config.room.bind_client_ids = bind_client_ids;
+        }
+        if let Some(push_state) = raw.room.push_state {
+            self.config.room.push_state = push_state;
+        }
+
+        if let Some(refresh_margin) = raw.auth.refresh_margin {
+            self.config.auth.refresh_margin = Duration::from_secs(refresh_margin);
+        }
+
+        Ok(self)
+    }
+}
+
+fn split_addrs(addrs: &str) -> Vec<String> {
+    addrs
+        .split(',')
+        .map(str::trim)
+        .filter(|addr| !addr.is_empty())
+        .map(str::to_owned)
+        .collect()
+}
+
+fn resolve(addr: &str) -> Result<SocketAddr, Error> {
+    addr.to_socket_addrs()
+        .map_err(|err| Error::Invalid(format!("invalid bind address `{addr}`, err: {err}")))?
+        .next()
+        .ok_or_else(|| Error::Invalid(format!("cannot resolve bind address `{addr}`")))
+}
+
+fn read_env<T: FromStr>(key: &str) -> Result<Option<T>, Error>
+where
+    T::Err: fmt::Display,
+{
+    let key = format!("{ENV_PREFIX}{key}");
+
+    match env::var(&key) {
+        Ok(value) => value
+            .trim()
+            .parse()
+            .map(Some)
+            .map_err(|err| Error::Parse(format!("parse `{key}` failed, err: {err}"))),
+        Err(env::VarError::NotPresent) => Ok(None),
+        Err(err) => Err(Error::Parse(format!("read `{key}` failed, err: {err}"))),
+    }
+}
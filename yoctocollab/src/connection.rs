@@ -1,24 +1,49 @@
 use futures::{
+    ready,
     stream::{SplitSink, SplitStream},
-    StreamExt,
+    SinkExt, StreamExt,
 };
-use std::{cell::RefCell, future};
+use std::{cell::RefCell, collections::HashMap, future, sync::Arc, task::Poll, time::Duration};
 
 use tokio::{
-    net::TcpStream, sync::mpsc::UnboundedReceiver, task::futures::TaskLocalFuture, task_local,
+    net::TcpStream,
+    sync::mpsc::UnboundedReceiver,
+    task::futures::TaskLocalFuture,
+    task_local,
+    time::{sleep_until, Instant},
 };
-use tokio_tungstenite::{tungstenite::Message, WebSocketStream};
+use tokio_tungstenite::{
+    tungstenite::{Error, Message},
+    WebSocketStream,
+};
+
+use crate::{auth::Permission, extension::RequestInfo, protocol::Framing, room::RoomCommand};
+
+struct Attachment {
+    room_command: RoomCommand,
+    room_incoming: UnboundedReceiver<Message>,
+
+    /// the user of the token which attached the document
+    user_id: Option<String>,
+    /// when the token of the document expires
+    expires_at: Option<Instant>,
+    refresh_requested: bool,
+}
 
-use crate::room::RoomCommand;
+pub(super) enum Expiry {
+    /// the token expires within the refresh margin
+    Refresh,
+    Expired,
+}
 
 pub(super) struct Connection {
     connection_id: u64,
-    room_name: String,
+    request: Arc<RequestInfo>,
+    framing: Framing,
 
-    room_command: RoomCommand,
+    rooms: HashMap<String, Attachment>,
     stream_outgoing: SplitSink<WebSocketStream<TcpStream>, Message>,
     stream_incoming: SplitStream<WebSocketStream<TcpStream>>,
-    room_incoming: UnboundedReceiver<Message>,
 }
 
 task_local! {
@@ -28,21 +53,18 @@ task_local! {
 impl Connection {
     pub(super) fn new(
         connection_id: u64,
-        room_name: String,
-
-        room_command: RoomCommand,
-        room_incoming: UnboundedReceiver<Message>,
-
+        request: Arc<RequestInfo>,
+        framing: Framing,
         stream: WebSocketStream<TcpStream>,
     ) -> Self {
         let (stream_outgoing, stream_incoming) = stream.split();
 
         Self {
             connection_id,
-            room_name,
+            request,
+            framing,
 
-            room_command,
-            room_incoming,
+            rooms: HashMap::new(),
 
             stream_outgoing,
             stream_incoming,
@@ -56,3 +78,187 @@ pub(super) fn connection<F: future::Future>(
 ) -> TaskLocalFuture<RefCell<Connection>, F> {
     CONNECTION.scope(RefCell::new(conn), f)
 }
+
+pub(super) fn connection_id() -> u64 {
+    CONNECTION.with(|conn| conn.borrow().connection_id)
+}
+
+pub(super) fn request() -> Arc<RequestInfo> {
+    CONNECTION.with(|conn| conn.borrow().request.clone())
+}
+
+pub(super) fn framing() -> Framing {
+    CONNECTION.with(|conn| conn.borrow().framing.clone())
+}
+
+// the connection is only borrowed while it is polled, so these futures can be
+// raced against each other inside `tokio::select!`
+
+pub(super) async fn recv_stream() -> Option<Result<Message, Error>> {
+    future::poll_fn(|cx| {
+        CONNECTION.with(|conn| conn.borrow_mut().stream_incoming.poll_next_unpin(cx))
+    })
+    .await
+}
+
+/// Receives the next message sent by any attached room, `None` means the room
+/// has been destroyed. Stays pending while no room is attached.
+pub(super) async fn recv_room() -> (String, Option<Message>) {
+    future::poll_fn(|cx| {
+        CONNECTION.with(|conn| {
+            let mut conn = conn.borrow_mut();
+
+            for (name, attachment) in conn.rooms.iter_mut() {
+                if let Poll::Ready(msg) = attachment.room_incoming.poll_recv(cx) {
+                    return Poll::Ready((name.clone(), msg));
+                }
+            }
+
+            Poll::Pending
+        })
+    })
+    .await
+}
+
+pub(super) async fn send_stream(msg: Message) -> Result<(), Error> {
+    let mut msg = Some(msg);
+
+    future::poll_fn(|cx| {
+        CONNECTION.with(|conn| {
+            let stream_outgoing = &mut conn.borrow_mut().stream_outgoing;
+
+            if msg.is_some() {
+                ready!(stream_outgoing.poll_ready_unpin(cx))?;
+                if let Some(msg) = msg.take() {
+                    stream_outgoing.start_send_unpin(msg)?;
+                }
+            }
+
+            stream_outgoing.poll_flush_unpin(cx)
+        })
+    })
+    .await
+}
+
+pub(super) fn is_attached(name: &str) -> bool {
+    CONNECTION.with(|conn| conn.borrow().rooms.contains_key(name))
+}
+
+pub(super) fn attach_room(
+    name: String,
+    room_command: RoomCommand,
+    room_incoming: UnboundedReceiver<Message>,
+    user_id: Option<String>,
+    expires_at: Option<Instant>,
+) {
+    CONNECTION.with(|conn| {
+        conn.borrow_mut().rooms.insert(
+            name,
+            Attachment {
+                room_command,
+                room_incoming,
+
+                user_id,
+                expires_at,
+                refresh_requested: false,
+            },
+        );
+    });
+}
+
+/// The user of an attached room, `None` without a user or when the room is
+/// not attached.
+pub(super) fn room_user_id(name: &str) -> Option<String> {
+    CONNECTION.with(|conn| conn.borrow().rooms.get(name)?.user_id.clone())
+}
+
+/// Applies a refreshed token to an attached room.
+pub(super) fn renew_room(
+    name: &str,
+    permission: Permission,
+    expires_at: Option<Instant>,
+) -> Result<(), ()> {
+    CONNECTION.with(|conn| {
+        let mut conn = conn.borrow_mut();
+        let connection_id = conn.connection_id;
+
+        let attachment = conn.rooms.get_mut(name).ok_or(())?;
+        attachment.expires_at = expires_at;
+        attachment.refresh_requested = false;
+        attachment
+            .room_command
+            .permission(connection_id, permission)
+    })
+}
+
+pub(super) fn request_refresh(name: &str) {
+    CONNECTION.with(|conn| {
+        if let Some(attachment) = conn.borrow_mut().rooms.get_mut(name) {
+            attachment.refresh_requested = true;
+        }
+    });
+}
+
+/// Waits until the token of an attached room has to be refreshed, or has
+/// expired. Stays pending while no token expires.
+pub(super) async fn next_expiry(refresh_margin: Duration) -> (String, Expiry) {
+    let next = CONNECTION.with(|conn| {
+        conn.borrow()
+            .rooms
+            .iter()
+            .filter_map(|(name, attachment)| {
+                let expires_at = attachment.expires_at?;
+                Some(if attachment.refresh_requested {
+                    (expires_at, name, Expiry::Expired)
+                } else {
+                    let refresh_at = expires_at
+                        .checked_sub(refresh_margin)
+                        .unwrap_or_else(Instant::now);
+                    (refresh_at, name, Expiry::Refresh)
+                })
+            })
+            .min_by_key(|(deadline, _, _)| *deadline)
+            .map(|(deadline, name, expiry)| (deadline, name.clone(), expiry))
+    });
+
+    match next {
+        Some((deadline, name, expiry)) => {
+            sleep_until(deadline).await;
+            (name, expiry)
+        }
+        None => future::pending().await,
+    }
+}
+
+/// Detaches the room, closing the receiver lets the room know that the
+/// connection no longer listens to it.
+pub(super) fn detach_room(name: &str) {
+    CONNECTION.with(|conn| {
+        let mut conn = conn.borrow_mut();
+        let connection_id = conn.connection_id;
+
+        if let Some(mut attachment) = conn.rooms.remove(name) {
+            attachment.room_incoming.close();
+            if attachment.room_command.leave(connection_id).is_err() {
+                log::warn!("room `{name}` already destroyed");
+            }
+        }
+    });
+}
+
+pub(super) fn detach_all_rooms() {
+    let names = CONNECTION.with(|conn| conn.borrow().rooms.keys().cloned().collect::<Vec<_>>());
+    for name in names {
+        detach_room(&name);
+    }
+}
+
+pub(super) fn send_room(name: &str, msg: Vec<u8>) -> Result<(), ()> {
+    CONNECTION.with(|conn| {
+        let conn = conn.borrow();
+        match conn.rooms.get(name) {
+            Some(attachment) => attachment.room_command.message(conn.connection_id, msg),
+            None => Err(()),
+        }
+    })
+}
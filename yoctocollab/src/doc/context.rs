@@ -1,24 +1,32 @@
-use tokio::sync::mpsc::UnboundedSender;
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
 use tokio_tungstenite::tungstenite::{
     protocol::{frame::coding::CloseCode, CloseFrame},
     Message,
 };
 
-use crate::protocol::Context;
+use crate::{
+    auth::Permission,
+    extension::StatelessPayload,
+    protocol::{Awareness, AwarenessChanges, Context, DeleteRanges},
+};
 
-use super::document::Document;
+use super::document::{Document, Peer};
 
 pub(super) struct DocumentContext<'s> {
     document: &'s mut Document,
+    cid: u64,
     connection: UnboundedSender<Message>,
+    permission: Permission,
     closed: bool,
 }
 
 impl<'s> DocumentContext<'s> {
-    pub(super) fn new(document: &'s mut Document, connection: UnboundedSender<Message>) -> Self {
+    pub(super) fn new(document: &'s mut Document, cid: u64, peer: Peer) -> Self {
         Self {
             document,
-            connection,
+            cid,
+            connection: peer.sender,
+            permission: peer.permission,
             closed: false,
         }
     }
@@ -29,15 +37,91 @@ impl<'s> DocumentContext<'s> {
 }
 
 impl<'s> Context for DocumentContext<'s> {
+    async fn on_stateless(&mut self, payload: &str) -> Vec<String> {
+        let payload = StatelessPayload {
+            document_name: &self.document.name,
+            connection_id: self.cid,
+            payload,
+        };
+        match self.document.extensions.on_stateless(payload).await {
+            Ok(replies) => replies,
+            Err(reject) => {
+                log::warn!("reject stateless message, reason: {reject}");
+                Vec::new()
+            }
+        }
+    }
+
+    fn binds_clients(&self) -> bool {
+        self.document.client_owners.is_some()
+    }
+
+    fn bind_clients(&mut self, clients: &[u64]) -> bool {
+        let Some(client_owners) = self.document.client_owners.as_mut() else {
+            return true;
+        };
+        if clients.iter().any(|client| {
+            client_owners
+                .get(client)
+                .is_some_and(|owner| *owner != self.cid)
+        }) {
+            return false;
+        }
+
+        for client in clients {
+            client_owners.insert(*client, self.cid);
+        }
+
+        true
+    }
+
+    fn owns_awareness(&self, client_id: u64) -> bool {
+        self.document
+            .awareness_owners
+            .get(&client_id)
+            .is_none_or(|owner| *owner == self.cid)
+    }
+
+    fn on_awareness_update(&mut self, changes: &AwarenessChanges) {
+        let now = Instant::now();
+        for client_id in changes.added.iter().chain(changes.updated.iter()) {
+            self.document.awareness_owners.insert(*client_id, self.cid);
+            self.document.awareness_updated_at.insert(*client_id, now);
+        }
+        // the client stays bound to the connection until it leaves
+        for client_id in &changes.removed {
+            self.document.awareness_updated_at.remove(client_id);
+        }
+
+        self.document
+            .awareness_changes
+            .push((Some(self.cid), changes.clone()));
+    }
+
     fn unicast(&self, msg: Vec<u8>) {
         if self.connection.send(Message::Binary(msg)).is_err() {
             log::error!("unicast message failed");
         }
     }
 
+    fn on_update(&mut self, update: &[u8]) {
+        self.document.updates.push((self.cid, update.to_owned()));
+    }
+
     fn broadcast(&self, msg: Vec<u8>) {
-        for (_, connection) in self.document.connections.iter() {
-            if connection.send(Message::Binary(msg.clone())).is_err() {
+        for (_, peer) in self.document.connections.iter() {
+            if peer.sender.send(Message::Binary(msg.clone())).is_err() {
+                log::error!("broadcast message failed");
+            }
+        }
+    }
+
+    fn broadcast_others(&self, msg: Vec<u8>) {
+        for (cid, peer) in self.document.connections.iter() {
+            if *cid == self.cid {
+                continue;
+            }
+            if peer.sender.send(Message::Binary(msg.clone())).is_err() {
                 log::error!("broadcast message failed");
             }
         }
@@ -51,11 +135,19 @@ impl<'s> Context for DocumentContext<'s> {
         &mut self.document.doc
     }
 
-    fn get_awareness(&self) -> &y_octo::Awareness {
+    fn get_delete_ranges(&self) -> &DeleteRanges {
+        &self.document.deleted
+    }
+
+    fn get_delete_ranges_mut(&mut self) -> &mut DeleteRanges {
+        &mut self.document.deleted
+    }
+
+    fn get_awareness(&self) -> &Awareness {
         &self.document.awareness
     }
 
-    fn get_awareness_mut(&mut self) -> &mut y_octo::Awareness {
+    fn get_awareness_mut(&mut self) -> &mut Awareness {
         &mut self.document.awareness
     }
 
@@ -63,6 +155,10 @@ impl<'s> Context for DocumentContext<'s> {
         &self.document.name
     }
 
+    fn get_permission(&self) -> Permission {
+        self.permission
+    }
+
     async fn close(&mut self) {
         if self
             .connection
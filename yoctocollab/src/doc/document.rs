@@ -1,70 +1,290 @@
-use std::{collections::HashMap, u64};
+use std::{collections::HashMap, sync::Arc, time::Duration};
 
-use tokio::sync::mpsc::UnboundedSender;
-use tokio_tungstenite::tungstenite::Message;
-use y_octo::{Awareness, Doc, JwstCodecResult};
+use tokio::{sync::mpsc::UnboundedSender, time::Instant};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+use y_octo::{Doc, JwstCodecResult, StateVector};
 
-use crate::protocol::{handle_message, handle_query_awareness};
+use crate::{
+    auth::Permission,
+    extension::Extensions,
+    protocol::{
+        document_header, handle_join, handle_message, handle_sync_status, remove_awareness_states,
+        Awareness, AwarenessChanges, DeleteRanges,
+    },
+};
 
 use super::context::DocumentContext;
 
+/// A connection joined to the document.
+#[derive(Clone)]
+pub(super) struct Peer {
+    pub(super) sender: UnboundedSender<Message>,
+    pub(super) permission: Permission,
+    /// the user of the authenticated connection
+    pub(super) user_id: Option<String>,
+}
+
 pub struct Document {
     pub(super) name: String,
     pub(super) doc: Doc,
+    /// the deleted clocks of `doc`, updates deleting nothing new are no-ops
+    pub(super) deleted: DeleteRanges,
     pub(super) awareness: Awareness,
+    pub(super) extensions: Arc<Extensions>,
+
+    pub(super) connections: HashMap<u64, Peer>,
+    /// the connection which first announced each awareness client id, only it
+    /// may change the state until it leaves or the state times out
+    pub(super) awareness_owners: HashMap<u64, u64>,
+    /// when the clock of each awareness client id last advanced
+    pub(super) awareness_updated_at: HashMap<u64, Instant>,
+    /// awareness changes since the last `take_awareness_changes`, with the
+    /// connection which made them
+    pub(super) awareness_changes: Vec<(Option<u64>, AwarenessChanges)>,
+
+    /// the connection which authored each Yjs client id, `None` when client
+    /// ids are not bound
+    pub(super) client_owners: Option<HashMap<u64, u64>>,
+    /// whether read-only connections receive the full document on join
+    pub(super) push_state: bool,
 
-    pub(super) connections: HashMap<u64, UnboundedSender<Message>>,
+    /// updates applied since the last `take_updates`, with the connection
+    /// which sent them
+    pub(super) updates: Vec<(u64, Vec<u8>)>,
 }
 
 impl Document {
-    pub fn new(name: String, doc: Doc) -> Self {
+    pub fn new(
+        name: String,
+        doc: Doc,
+        extensions: Arc<Extensions>,
+        bind_client_ids: bool,
+        push_state: bool,
+    ) -> Self {
+        // without them every deletion counts as a change, which is only slower
+        let deleted = DeleteRanges::from_doc(&doc).unwrap_or_else(|err| {
+            log::error!("read delete set of `{name}` failed, err: {err}");
+            DeleteRanges::default()
+        });
+
         Self {
             name,
             doc,
-            awareness: Awareness::new(0),
+            deleted,
+            awareness: Awareness::default(),
+            extensions,
 
             connections: HashMap::new(),
+            awareness_owners: HashMap::new(),
+            awareness_updated_at: HashMap::new(),
+            awareness_changes: Vec::new(),
+
+            client_owners: bind_client_ids.then(HashMap::new),
+            push_state,
+
+            updates: Vec::new(),
         }
     }
 
     pub fn connect(
         &mut self,
         cid: u64,
-        connection: UnboundedSender<Message>,
+        sender: UnboundedSender<Message>,
+        permission: Permission,
+        user_id: Option<String>,
     ) -> JwstCodecResult<()> {
-        let ctx = DocumentContext::new(self, connection.clone());
-        handle_query_awareness(&ctx)?;
+        let peer = Peer {
+            sender,
+            permission,
+            user_id,
+        };
+        let push_state = self.push_state && permission < Permission::Write;
+        let ctx = DocumentContext::new(self, cid, peer.clone());
+        handle_join(&ctx, push_state)?;
 
-        self.connections.insert(cid, connection);
+        self.connections.insert(cid, peer);
 
         Ok(())
     }
 
-    pub fn disconnect(&mut self, cid: u64) {
-        self.connections.remove(&cid);
+    /// Returns whether the connection was still connected.
+    pub fn disconnect(&mut self, cid: u64) -> bool {
+        self.remove_connection(cid).is_some()
+    }
+
+    /// Removes the connection together with the awareness states it
+    /// announced, the remaining connections are told about the removal.
+    fn remove_connection(&mut self, cid: u64) -> Option<Peer> {
+        let peer = self.connections.remove(&cid)?;
+
+        if let Some(client_owners) = &mut self.client_owners {
+            client_owners.retain(|_, owner| *owner != cid);
+        }
+
+        let client_ids = self
+            .awareness_owners
+            .iter()
+            .filter(|(_, owner)| **owner == cid)
+            .map(|(client_id, _)| *client_id)
+            .collect::<Vec<_>>();
+        self.remove_awareness(&client_ids);
+
+        Some(peer)
+    }
+
+    /// When the next awareness state times out.
+    pub(crate) fn awareness_deadline(&self, timeout: Duration) -> Option<Instant> {
+        if timeout.is_zero() {
+            return None;
+        }
+
+        self.awareness_updated_at
+            .values()
+            .min()
+            .map(|updated_at| *updated_at + timeout)
+    }
+
+    /// Removes the awareness states whose clock did not advance within the
+    /// timeout, their clients are assumed to be gone.
+    pub(crate) fn expire_awareness(&mut self, timeout: Duration) {
+        let now = Instant::now();
+        let client_ids = self
+            .awareness_updated_at
+            .iter()
+            .filter(|(_, updated_at)| **updated_at + timeout <= now)
+            .map(|(client_id, _)| *client_id)
+            .collect::<Vec<_>>();
+        self.remove_awareness(&client_ids);
+    }
+
+    fn remove_awareness(&mut self, client_ids: &[u64]) {
+        if client_ids.is_empty() {
+            return;
+        }
+
+        for client_id in client_ids {
+            self.awareness_owners.remove(client_id);
+            self.awareness_updated_at.remove(client_id);
+        }
+
+        let (changes, msg) = match remove_awareness_states(&mut self.awareness, client_ids) {
+            Ok(Some(removed)) => removed,
+            Ok(None) => return,
+            Err(err) => {
+                log::error!("remove awareness states failed, err: {err}");
+                return;
+            }
+        };
+        self.awareness_changes.push((None, changes));
+
+        let msg = match document_header(&self.name) {
+            Ok(head) => [head, msg].concat(),
+            Err(err) => {
+                log::error!("write awareness message failed, err: {err}");
+                return;
+            }
+        };
+        for peer in self.connections.values() {
+            if peer.sender.send(Message::Binary(msg.clone())).is_err() {
+                log::error!("broadcast message failed");
+            }
+        }
+    }
+
+    /// Closes the connection with the reason and disconnects it.
+    pub(crate) fn close(&mut self, cid: u64, reason: &str) -> bool {
+        let peer = if let Some(peer) = self.remove_connection(cid) {
+            peer
+        } else {
+            return false;
+        };
+
+        let frame = CloseFrame {
+            code: CloseCode::Policy,
+            reason: reason.to_owned().into(),
+        };
+        if peer.sender.send(Message::Close(Some(frame))).is_err() {
+            log::error!("close connection failed");
+        }
+
+        true
+    }
+
+    pub(crate) fn set_permission(&mut self, cid: u64, permission: Permission) {
+        if let Some(peer) = self.connections.get_mut(&cid) {
+            peer.permission = permission;
+        }
+    }
+
+    pub(crate) fn user_id(&self, cid: u64) -> Option<&str> {
+        self.connections.get(&cid)?.user_id.as_deref()
+    }
+
+    pub(crate) fn is_connected(&self, cid: u64) -> bool {
+        self.connections.contains_key(&cid)
     }
 
     pub async fn handle_message(&mut self, cid: u64, message: &[u8]) -> JwstCodecResult<()> {
-        let connection = if let Some(connection) = self.connections.get(&cid) {
-            connection.clone()
+        let peer = if let Some(peer) = self.connections.get(&cid) {
+            peer.clone()
         } else {
             return Ok(());
         };
 
-        let mut ctx = DocumentContext::new(self, connection);
+        let mut ctx = DocumentContext::new(self, cid, peer);
         handle_message(&mut ctx, message).await?;
 
         if ctx.is_closed() {
-            self.connections.remove(&cid);
+            self.remove_connection(cid);
         }
 
         Ok(())
     }
 
+    /// Tells the connection whether its updates are durable.
+    pub(crate) fn sync_status(&mut self, cid: u64, saved: bool) -> JwstCodecResult<()> {
+        let peer = if let Some(peer) = self.connections.get(&cid) {
+            peer.clone()
+        } else {
+            return Ok(());
+        };
+
+        let ctx = DocumentContext::new(self, cid, peer);
+        handle_sync_status(&ctx, saved)
+    }
+
     pub(crate) fn is_connection_empty(&self) -> bool {
         self.connections.is_empty()
     }
 
+    pub(crate) fn connection_count(&self) -> usize {
+        self.connections.len()
+    }
+
+    pub(crate) fn take_updates(&mut self) -> Vec<(u64, Vec<u8>)> {
+        std::mem::take(&mut self.updates)
+    }
+
+    pub(crate) fn take_awareness_changes(&mut self) -> Vec<(Option<u64>, AwarenessChanges)> {
+        std::mem::take(&mut self.awareness_changes)
+    }
+
+    pub(crate) fn encode_state(&self) -> JwstCodecResult<Vec<u8>> {
+        self.doc.encode_state_as_update_v1(&StateVector::default())
+    }
+
+    /// Encodes the structs added since the state with the delete set.
+    pub(crate) fn encode_diff(&self, state: &StateVector) -> JwstCodecResult<Vec<u8>> {
+        self.doc.encode_state_as_update_v1(state)
+    }
+
+    pub(crate) fn state_vector(&self) -> StateVector {
+        self.doc.get_state_vector()
+    }
+
     pub(crate) fn get_name(&self) -> &str {
         &self.name
     }
@@ -0,0 +1,271 @@
+use std::{fmt, sync::Arc};
+
+use futures::{
+    future::{ready, BoxFuture},
+    FutureExt,
+};
+use tokio_tungstenite::tungstenite::http::{HeaderMap, Uri};
+use y_octo::Doc;
+
+use crate::auth::Authentication;
+
+/// Refuses a connection or a message, the reason is sent back to the client.
+#[derive(Debug, Clone)]
+pub struct Reject {
+    reason: String,
+}
+
+impl Reject {
+    pub fn new<S: Into<String>>(reason: S) -> Self {
+        Self {
+            reason: reason.into(),
+        }
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+}
+
+impl fmt::Display for Reject {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.reason)
+    }
+}
+
+pub type HookResult = Result<(), Reject>;
+
+/// The handshake request of a websocket connection.
+#[derive(Debug, Clone)]
+pub struct RequestInfo {
+    pub uri: Uri,
+    pub headers: HeaderMap,
+}
+
+#[derive(Clone, Copy)]
+pub struct ConnectPayload<'a> {
+    pub connection_id: u64,
+    pub request: &'a RequestInfo,
+}
+
+#[derive(Clone, Copy)]
+pub struct AuthenticatePayload<'a> {
+    pub connection_id: u64,
+    pub document_name: &'a str,
+    /// the token of the `Auth` message, `None` when the document is opened
+    /// without authentication
+    pub token: Option<&'a str>,
+    /// the result of the authenticator, if one is registered
+    pub authentication: Option<&'a Authentication>,
+    pub request: &'a RequestInfo,
+}
+
+pub struct LoadDocumentPayload<'a> {
+    pub document_name: &'a str,
+    /// the document restored from the store, hooks may fill a new document
+    pub doc: &'a mut Doc,
+}
+
+#[derive(Clone, Copy)]
+pub struct MessagePayload<'a> {
+    pub document_name: &'a str,
+    pub connection_id: u64,
+    pub message: &'a [u8],
+}
+
+#[derive(Clone, Copy)]
+pub struct ChangePayload<'a> {
+    pub document_name: &'a str,
+    pub connection_id: u64,
+    pub update: &'a [u8],
+}
+
+#[derive(Clone, Copy)]
+pub struct StoreDocumentPayload<'a> {
+    pub document_name: &'a str,
+}
+
+#[derive(Clone, Copy)]
+pub struct DisconnectPayload<'a> {
+    pub document_name: &'a str,
+    pub connection_id: u64,
+}
+
+#[derive(Clone, Copy)]
+pub struct DestroyPayload<'a> {
+    pub document_name: &'a str,
+}
+
+#[derive(Clone, Copy)]
+pub struct AwarenessPayload<'a> {
+    pub document_name: &'a str,
+    /// the connection which sent the update, `None` when the server removed
+    /// the clients, e.g. on disconnect or timeout
+    pub connection_id: Option<u64>,
+    pub added: &'a [u64],
+    /// clients whose clock advanced, even if their state is unchanged
+    pub updated: &'a [u64],
+    pub removed: &'a [u64],
+}
+
+#[derive(Clone, Copy)]
+pub struct StatelessPayload<'a> {
+    pub document_name: &'a str,
+    pub connection_id: u64,
+    pub payload: &'a str,
+}
+
+/// Hooks into the lifecycle of the server, every hook does nothing by default.
+///
+/// Extensions run in the order they are registered, the first rejection stops
+/// the remaining extensions.
+pub trait Extension: Send + Sync + 'static {
+    /// A websocket connection completed its handshake.
+    fn on_connect<'a>(&'a self, _payload: ConnectPayload<'a>) -> BoxFuture<'a, HookResult> {
+        ready(Ok(())).boxed()
+    }
+
+    /// A connection is about to attach to a document, or refreshes the token
+    /// of an attached one.
+    fn on_authenticate<'a>(
+        &'a self,
+        _payload: AuthenticatePayload<'a>,
+    ) -> BoxFuture<'a, HookResult> {
+        ready(Ok(())).boxed()
+    }
+
+    /// A document has been loaded from the store inside its room, before the
+    /// room serves the connections which joined while it was loading.
+    fn on_load_document<'a>(
+        &'a self,
+        _payload: LoadDocumentPayload<'a>,
+    ) -> BoxFuture<'a, HookResult> {
+        ready(Ok(())).boxed()
+    }
+
+    /// A protocol message is about to be handled by the room.
+    fn on_message<'a>(&'a self, _payload: MessagePayload<'a>) -> BoxFuture<'a, HookResult> {
+        ready(Ok(())).boxed()
+    }
+
+    /// An update has been applied to the document.
+    fn on_change<'a>(&'a self, _payload: ChangePayload<'a>) -> BoxFuture<'a, ()> {
+        ready(()).boxed()
+    }
+
+    /// Pending changes of the document have been stored.
+    fn on_store_document<'a>(&'a self, _payload: StoreDocumentPayload<'a>) -> BoxFuture<'a, ()> {
+        ready(()).boxed()
+    }
+
+    /// A connection has left the document.
+    fn on_disconnect<'a>(&'a self, _payload: DisconnectPayload<'a>) -> BoxFuture<'a, ()> {
+        ready(()).boxed()
+    }
+
+    /// The room of the document has been destroyed.
+    fn on_destroy<'a>(&'a self, _payload: DestroyPayload<'a>) -> BoxFuture<'a, ()> {
+        ready(()).boxed()
+    }
+
+    /// The awareness clients of the document have changed.
+    fn on_awareness_update<'a>(&'a self, _payload: AwarenessPayload<'a>) -> BoxFuture<'a, ()> {
+        ready(()).boxed()
+    }
+
+    /// A stateless message has been received, the returned payload is
+    /// replied to the connection as a stateless message.
+    fn on_stateless<'a>(
+        &'a self,
+        _payload: StatelessPayload<'a>,
+    ) -> BoxFuture<'a, Result<Option<String>, Reject>> {
+        ready(Ok(None)).boxed()
+    }
+}
+
+#[derive(Clone, Default)]
+pub(crate) struct Extensions {
+    extensions: Vec<Arc<dyn Extension>>,
+}
+
+impl Extensions {
+    pub(crate) fn register<E: Extension>(&mut self, extension: E) {
+        self.extensions.push(Arc::new(extension));
+    }
+
+    pub(crate) async fn on_connect(&self, payload: ConnectPayload<'_>) -> HookResult {
+        for extension in self.extensions.iter() {
+            extension.on_connect(payload).await?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn on_authenticate(&self, payload: AuthenticatePayload<'_>) -> HookResult {
+        for extension in self.extensions.iter() {
+            extension.on_authenticate(payload).await?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn on_load_document(&self, document_name: &str, doc: &mut Doc) -> HookResult {
+        for extension in self.extensions.iter() {
+            extension
+                .on_load_document(LoadDocumentPayload { document_name, doc })
+                .await?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn on_message(&self, payload: MessagePayload<'_>) -> HookResult {
+        for extension in self.extensions.iter() {
+            extension.on_message(payload).await?;
+        }
+
+        Ok(())
+    }
+
+    pub(crate) async fn on_change(&self, payload: ChangePayload<'_>) {
+        for extension in self.extensions.iter() {
+            extension.on_change(payload).await;
+        }
+    }
+
+    pub(crate) async fn on_store_document(&self, payload: StoreDocumentPayload<'_>) {
+        for extension in self.extensions.iter() {
+            extension.on_store_document(payload).await;
+        }
+    }
+
+    pub(crate) async fn on_disconnect(&self, payload: DisconnectPayload<'_>) {
+        for extension in self.extensions.iter() {
+            extension.on_disconnect(payload).await;
+        }
+    }
+
+    pub(crate) async fn on_destroy(&self, payload: DestroyPayload<'_>) {
+        for extension in self.extensions.iter() {
+            extension.on_destroy(payload).await;
+        }
+    }
+
+    pub(crate) async fn on_awareness_update(&self, payload: AwarenessPayload<'_>) {
+        for extension in self.extensions.iter() {
+            extension.on_awareness_update(payload).await;
+        }
+    }
+
+    pub(crate) async fn on_stateless(
+        &self,
+        payload: StatelessPayload<'_>,
+    ) -> Result<Vec<String>, Reject> {
+        let mut replies = Vec::new();
+        for extension in self.extensions.iter() {
+            replies.extend(extension.on_stateless(payload).await?);
+        }
+
+        Ok(replies)
+    }
+}
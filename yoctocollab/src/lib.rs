@@ -1,6 +1,29 @@
+mod audit;
+mod auth;
+mod config;
 mod connection;
 mod doc;
+mod extension;
 mod protocol;
 mod room;
+mod route;
 mod server;
+mod storage;
 mod utils;
+
+#[cfg(feature = "sqlite")]
+pub use audit::SqliteAuditSink;
+pub use audit::{AuditRecord, AuditSink, Error as AuditError, FileAuditSink};
+pub use auth::{AuthRequest, Authentication, Authenticator, Permission};
+#[cfg(feature = "jwt")]
+pub use auth::{Error as JwtError, JwtAuthenticator};
+pub use config::{AuthConfig, Error as ConfigError, RoomConfig, ServerConfig, ServerConfigBuilder};
+pub use extension::{
+    AuthenticatePayload, AwarenessPayload, ChangePayload, ConnectPayload, DestroyPayload,
+    DisconnectPayload, Extension, HookResult, LoadDocumentPayload, MessagePayload, Reject,
+    RequestInfo, StatelessPayload, StoreDocumentPayload,
+};
+pub use server::Server;
+#[cfg(feature = "sqlite")]
+pub use storage::{DocumentMetadata, SqliteStore};
+pub use storage::{DocumentStore, Error as StorageError, FsStore, FsyncPolicy, MemoryStore};
@@ -0,0 +1,60 @@
+use std::io;
+
+use y_octo::{write_var_string, write_var_u64, JwstCodecError, JwstCodecResult};
+
+use super::message_type::{AuthMessage, MessageType};
+
+/// the auth message type of y-protocols denying the connection
+const Y_PERMISSION_DENIED: u64 = 0;
+
+pub fn write_authenticated(scope: &str) -> JwstCodecResult<Vec<u8>> {
+    write_auth_inline(AuthMessage::Authenticated, scope)
+        .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))
+}
+
+pub fn write_permission_denied(reason: &str) -> JwstCodecResult<Vec<u8>> {
+    write_auth_inline(AuthMessage::PermissionDenied, reason)
+        .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))
+}
+
+/// Writes the denial of y-protocols, whose auth messages only know this one.
+pub fn write_y_permission_denied(reason: &str) -> JwstCodecResult<Vec<u8>> {
+    write_y_permission_denied_inline(reason)
+        .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))
+}
+
+pub fn write_token_required() -> JwstCodecResult<Vec<u8>> {
+    write_token_required_inline().map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))
+}
+
+#[inline]
+fn write_token_required_inline() -> Result<Vec<u8>, io::Error> {
+    let mut auth = Vec::with_capacity(2);
+
+    write_var_u64(&mut auth, MessageType::Auth.into())?;
+    write_var_u64(&mut auth, AuthMessage::TokenRequired.into())?;
+
+    Ok(auth)
+}
+
+#[inline]
+fn write_y_permission_denied_inline(reason: &str) -> Result<Vec<u8>, io::Error> {
+    let mut auth = Vec::with_capacity(11 + reason.len());
+
+    write_var_u64(&mut auth, MessageType::Auth.into())?;
+    write_var_u64(&mut auth, Y_PERMISSION_DENIED)?;
+    write_var_string(&mut auth, reason)?;
+
+    Ok(auth)
+}
+
+#[inline]
+fn write_auth_inline(typ: AuthMessage, value: &str) -> Result<Vec<u8>, io::Error> {
+    let mut auth = Vec::with_capacity(11 + value.len());
+
+    write_var_u64(&mut auth, MessageType::Auth.into())?;
+    write_var_u64(&mut auth, typ.into())?;
+    write_var_string(&mut auth, value)?;
+
+    Ok(auth)
+}
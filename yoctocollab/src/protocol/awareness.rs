@@ -1,8 +1,149 @@
+use std::{collections::HashMap, io};
+
 use y_octo::{
-    read_var_buffer, AwarenessState, AwarenessStates, CrdtReader, JwstCodecResult, RawDecoder,
+    read_var_buffer, write_var_buffer, write_var_string, write_var_u64, CrdtReader, JwstCodecError,
+    JwstCodecResult, RawDecoder,
 };
 
-pub fn read_awareness_update(message: &[u8]) -> JwstCodecResult<AwarenessStates> {
+use super::message_type::MessageType;
+
+/// the content y-protocols sends for a removed client
+const NULL_CONTENT: &str = "null";
+/// clocks are JavaScript numbers in y-protocols, larger ones are not sent by
+/// any client
+const MAX_CLOCK: u64 = 1 << 53;
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AwarenessState {
+    clock: u64,
+    /// `None` once the client is removed, the clock is still kept so that
+    /// stale updates of the client are ignored
+    content: Option<String>,
+}
+
+impl AwarenessState {
+    pub fn new(clock: u64, content: String) -> Self {
+        let content = (content != NULL_CONTENT).then_some(content);
+
+        Self { clock, content }
+    }
+
+    pub fn is_deleted(&self) -> bool {
+        self.content.is_none()
+    }
+}
+
+/// The clients changed by an awareness update.
+#[derive(Debug, Clone, Default)]
+pub struct AwarenessChanges {
+    pub added: Vec<u64>,
+    /// clients whose clock advanced, even if the content stayed the same
+    pub updated: Vec<u64>,
+    pub removed: Vec<u64>,
+}
+
+impl AwarenessChanges {
+    pub fn is_empty(&self) -> bool {
+        self.added.is_empty() && self.updated.is_empty() && self.removed.is_empty()
+    }
+
+    pub fn client_ids(&self) -> impl Iterator<Item = u64> + '_ {
+        self.added
+            .iter()
+            .chain(self.updated.iter())
+            .chain(self.removed.iter())
+            .copied()
+    }
+}
+
+/// The awareness CRDT of y-protocols, without a local client.
+#[derive(Debug, Default)]
+pub struct Awareness {
+    states: HashMap<u64, AwarenessState>,
+}
+
+impl Awareness {
+    /// Applies the states of a remote update, a state wins when its clock is
+    /// newer, or equal while removing the client.
+    pub fn apply_update(&mut self, update: Vec<(u64, AwarenessState)>) -> AwarenessChanges {
+        let mut changes = AwarenessChanges::default();
+
+        for (client_id, state) in update {
+            let prev = self.states.get(&client_id);
+            let newer = match prev {
+                Some(prev) => {
+                    prev.clock < state.clock
+                        || (prev.clock == state.clock && state.is_deleted() && !prev.is_deleted())
+                }
+                None => true,
+            };
+            if !newer {
+                continue;
+            }
+
+            let alive_before = prev.is_some_and(|prev| !prev.is_deleted());
+            match (alive_before, state.is_deleted()) {
+                (false, false) => changes.added.push(client_id),
+                (true, false) => changes.updated.push(client_id),
+                (true, true) => changes.removed.push(client_id),
+                (false, true) => {}
+            }
+
+            self.states.insert(client_id, state);
+        }
+
+        changes
+    }
+
+    /// Removes the clients as if they removed themselves, a removal wins at
+    /// an equal clock should the clock not advance.
+    pub fn remove(&mut self, client_ids: &[u64]) -> AwarenessChanges {
+        let update = client_ids
+            .iter()
+            .filter_map(|client_id| {
+                let state = self.states.get(client_id)?;
+                (!state.is_deleted()).then_some((
+                    *client_id,
+                    AwarenessState {
+                        clock: state.clock.saturating_add(1),
+                        content: None,
+                    },
+                ))
+            })
+            .collect();
+
+        self.apply_update(update)
+    }
+
+    /// Writes an awareness message holding the states of the clients.
+    pub fn encode<I: IntoIterator<Item = u64>>(&self, client_ids: I) -> JwstCodecResult<Vec<u8>> {
+        let states = client_ids
+            .into_iter()
+            .filter_map(|client_id| Some((client_id, self.states.get(&client_id)?)))
+            .collect::<Vec<_>>();
+
+        write_awareness_inline(&states)
+            .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))
+    }
+
+    /// Writes an awareness message holding every present client, `None`
+    /// when there is none.
+    pub fn encode_present(&self) -> JwstCodecResult<Option<Vec<u8>>> {
+        let client_ids = self
+            .states
+            .iter()
+            .filter(|(_, state)| !state.is_deleted())
+            .map(|(client_id, _)| *client_id)
+            .collect::<Vec<_>>();
+        if client_ids.is_empty() {
+            return Ok(None);
+        }
+
+        self.encode(client_ids).map(Some)
+    }
+}
+
+pub fn read_awareness_update(message: &[u8]) -> JwstCodecResult<Vec<(u64, AwarenessState)>> {
     let (_, update) = read_var_buffer(message).map_err(|err| err.map_input(|u| u.len()))?;
 
     let mut decoder = RawDecoder::new(update.to_owned());
@@ -13,10 +154,83 @@ pub fn read_awareness_update(message: &[u8]) -> JwstCodecResult<AwarenessStates>
     for _ in 0..len {
         let client_id = decoder.read_var_u64()?;
         let clock = decoder.read_var_u64()?;
+        if clock > MAX_CLOCK {
+            return Err(JwstCodecError::InvalidStructType(
+                "awareness clock beyond 2^53",
+            ));
+        }
         let content = decoder.read_var_string()?;
 
         states.push((client_id, AwarenessState::new(clock, content)));
     }
 
-    Ok(states.into_iter().collect())
+    Ok(states)
+}
+
+#[inline]
+fn write_awareness_inline(states: &[(u64, &AwarenessState)]) -> Result<Vec<u8>, io::Error> {
+    let mut update = Vec::new();
+    write_var_u64(&mut update, states.len() as u64)?;
+    for (client_id, state) in states {
+        write_var_u64(&mut update, *client_id)?;
+        write_var_u64(&mut update, state.clock)?;
+        write_var_string(
+            &mut update,
+            state.content.as_deref().unwrap_or(NULL_CONTENT),
+        )?;
+    }
+
+    let mut awareness = Vec::with_capacity(update.len() + 10);
+    write_var_u64(&mut awareness, MessageType::Awareness.into())?;
+    write_var_buffer(&mut awareness, &update)?;
+
+    Ok(awareness)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn message(client_id: u64, clock: u64, content: &str) -> Vec<u8> {
+        let mut update = Vec::new();
+        write_var_u64(&mut update, 1).unwrap();
+        write_var_u64(&mut update, client_id).unwrap();
+        write_var_u64(&mut update, clock).unwrap();
+        write_var_string(&mut update, content).unwrap();
+
+        let mut message = Vec::new();
+        write_var_buffer(&mut message, &update).unwrap();
+        message
+    }
+
+    #[test]
+    fn read_clock_beyond_max() {
+        assert_eq!(
+            read_awareness_update(&message(1, MAX_CLOCK, "{}")).unwrap()[0].1,
+            AwarenessState::new(MAX_CLOCK, "{}".to_owned())
+        );
+        assert!(read_awareness_update(&message(1, MAX_CLOCK + 1, "{}")).is_err());
+        assert!(read_awareness_update(&message(1, u64::MAX, "{}")).is_err());
+    }
+
+    #[test]
+    fn remove_at_max_clock() {
+        let mut awareness = Awareness::default();
+        awareness.apply_update(vec![(1, AwarenessState::new(u64::MAX, "{}".to_owned()))]);
+
+        assert_eq!(awareness.remove(&[1]).removed, [1]);
+        assert_eq!(awareness.encode_present().unwrap(), None);
+    }
+
+    #[test]
+    fn remove_ignores_stale_updates() {
+        let mut awareness = Awareness::default();
+        awareness.apply_update(vec![(1, AwarenessState::new(3, "{}".to_owned()))]);
+        awareness.remove(&[1]);
+
+        let changes = awareness.apply_update(vec![(1, AwarenessState::new(4, "{}".to_owned()))]);
+        assert!(changes.is_empty());
+        let changes = awareness.apply_update(vec![(1, AwarenessState::new(5, "{}".to_owned()))]);
+        assert_eq!(changes.added, [1]);
+    }
 }
@@ -0,0 +1,19 @@
+use std::io;
+
+use y_octo::{write_var_string, write_var_u64, JwstCodecError, JwstCodecResult};
+
+use super::message_type::MessageType;
+
+pub fn write_close(reason: &str) -> JwstCodecResult<Vec<u8>> {
+    write_close_inline(reason).map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))
+}
+
+#[inline]
+fn write_close_inline(reason: &str) -> Result<Vec<u8>, io::Error> {
+    let mut close = Vec::with_capacity(10 + reason.len());
+
+    write_var_u64(&mut close, MessageType::Close.into())?;
+    write_var_string(&mut close, reason)?;
+
+    Ok(close)
+}
@@ -1,16 +1,52 @@
-use y_octo::{Awareness, Doc};
+use y_octo::Doc;
+
+use crate::auth::Permission;
+
+use super::{
+    awareness::{Awareness, AwarenessChanges},
+    update::DeleteRanges,
+};
 
 pub trait Context {
     fn get_document_name(&self) -> &str;
 
+    /// The permission of the connection on the document.
+    fn get_permission(&self) -> Permission;
+
     fn get_document(&self) -> &Doc;
     fn get_document_mut(&mut self) -> &mut Doc;
 
+    /// The deleted clocks of the document, extended with every applied update.
+    fn get_delete_ranges(&self) -> &DeleteRanges;
+    fn get_delete_ranges_mut(&mut self) -> &mut DeleteRanges;
+
     fn get_awareness(&self) -> &Awareness;
     fn get_awareness_mut(&mut self) -> &mut Awareness;
 
+    /// Called with every update applied to the document.
+    fn on_update(&mut self, update: &[u8]);
+
+    /// Whether the Yjs clients authoring updates are bound to connections.
+    fn binds_clients(&self) -> bool;
+    /// Binds the Yjs clients authoring an update to the connection, returns
+    /// `false` without binding any when one is bound to another connection.
+    fn bind_clients(&mut self, clients: &[u64]) -> bool;
+
+    /// Whether the connection may change the awareness state of the client,
+    /// a client belongs to the connection which announced it first.
+    fn owns_awareness(&self, client_id: u64) -> bool;
+
+    /// Called with the awareness clients changed by the connection.
+    fn on_awareness_update(&mut self, changes: &AwarenessChanges);
+
+    /// Called with the payload of every stateless message, returns the
+    /// payloads replied to the connection.
+    async fn on_stateless(&mut self, payload: &str) -> Vec<String>;
+
     fn unicast(&self, msg: Vec<u8>);
     fn broadcast(&self, msg: Vec<u8>);
+    /// Sends to every connection except the current one.
+    fn broadcast_others(&self, msg: Vec<u8>);
 
     async fn close(&mut self);
 }
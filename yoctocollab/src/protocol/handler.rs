@@ -1,20 +1,37 @@
-use std::sync::{Arc, Mutex};
-
 use y_octo::{
-    read_var_string, read_var_u64, write_sync_message, write_var_string, AwarenessEvent,
-    JwstCodecError, JwstCodecResult, SyncMessage,
+    read_var_string, read_var_u64, write_var_string, JwstCodecError, JwstCodecResult, StateVector,
 };
 
+use crate::auth::Permission;
+
 use super::{
-    awareness::read_awareness_update,
+    auth::{write_permission_denied, write_y_permission_denied},
+    awareness::{read_awareness_update, Awareness, AwarenessChanges},
     context::Context,
-    message_type::{DocMessage, MessageType},
+    message_type::{AuthMessage, DocMessage, MessageType},
+    stateless::write_stateless,
     sync::{
         read_sync_step1, read_sync_step2, read_sync_update, write_sync_status, write_sync_step1,
         write_sync_step2, write_sync_update,
     },
+    update::{contains_update, read_update_structs, DeleteRanges, UpdateStructs},
 };
 
+const READ_ONLY: &str = "read_only";
+const FOREIGN_CLIENT: &str = "foreign_client";
+const INVALID_UPDATE: &str = "invalid_update";
+
+/// How the frames of a connection name their document.
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub enum Framing {
+    /// every frame starts with the name of its document, as Hocuspocus does
+    #[default]
+    Hocuspocus,
+    /// frames start with the message type, as y-websocket does, the
+    /// connection serves only the document named by its URL
+    YWebsocket(String),
+}
+
 pub async fn handle_message<CTX: Context>(ctx: &mut CTX, message: &[u8]) -> JwstCodecResult<()> {
     let (tail, name) = read_var_string_inline(message)?;
 
@@ -36,10 +53,18 @@ pub async fn handle_message<CTX: Context>(ctx: &mut CTX, message: &[u8]) -> Jwst
         MessageType::QueryAwareness => {
             handle_query_awareness(ctx)?;
         }
-        MessageType::Stateless => {}
-        MessageType::BroadcastStateless => {}
+        MessageType::Stateless => {
+            let (_, payload) = read_var_string_inline(tail)?;
+            for reply in ctx.on_stateless(&payload).await {
+                ctx.unicast([message_header(ctx)?, write_stateless(&reply)?].concat());
+            }
+        }
+        MessageType::BroadcastStateless => {
+            let (_, payload) = read_var_string_inline(tail)?;
+            ctx.broadcast_others([message_header(ctx)?, write_stateless(&payload)?].concat());
+        }
         MessageType::Auth => {
-            // server ignore, maybe custom impl it
+            // authentication is handled by the connection before joining
         }
         MessageType::Close => {
             ctx.close().await;
@@ -68,75 +93,303 @@ fn handle_sync_message<CTX: Context>(ctx: &mut CTX, message: &[u8]) -> JwstCodec
         }
         DocMessage::Step2 => {
             let update = read_sync_step2(tail)?;
+            let structs = read_update_structs(&update);
+            if !check_writable(ctx, &update, &structs)? {
+                return Ok(());
+            }
             let broadcast_update = write_sync_update(&update)?;
-            ctx.get_document_mut().apply_update_from_binary(update)?;
+            if !apply_update(ctx, &update, structs.ok().as_ref())? {
+                return Ok(());
+            }
+            ctx.on_update(&update);
 
             ctx.broadcast([message_header(ctx)?, broadcast_update].concat());
-            ctx.unicast([message_header(ctx)?, write_sync_status(true)?].concat());
         }
         DocMessage::Update => {
             let update = read_sync_update(tail)?;
+            let structs = read_update_structs(&update);
+            if !check_writable(ctx, &update, &structs)? {
+                return Ok(());
+            }
             let broadcast_update = write_sync_update(&update)?;
-            ctx.get_document_mut().apply_update_from_binary(update)?;
+            if !apply_update(ctx, &update, structs.ok().as_ref())? {
+                return Ok(());
+            }
+            ctx.on_update(&update);
 
             ctx.broadcast([message_header(ctx)?, broadcast_update].concat());
+        }
+    }
+
+    Ok(())
+}
+
+/// Denies updates of connections which may not write. Read-only clients
+/// still answer `SyncStep1`, an answer the document already contains is
+/// acknowledged as synced. `structs` are read from the update, an update
+/// they cannot be read from is denied alone when binding its clients needs
+/// them, the connection stays.
+fn check_writable<CTX: Context>(
+    ctx: &mut CTX,
+    update: &[u8],
+    structs: &JwstCodecResult<UpdateStructs>,
+) -> JwstCodecResult<bool> {
+    if ctx.get_permission() < Permission::Write {
+        let contained = structs.as_ref().is_ok_and(|structs| {
+            contains_update(ctx.get_document(), ctx.get_delete_ranges(), update, structs)
+                .unwrap_or_default()
+        });
+        if contained {
+            ctx.unicast([message_header(ctx)?, write_sync_status(true)?].concat());
+        } else {
+            log::warn!(
+                "read-only connection updates document `{}`, denied",
+                ctx.get_document_name()
+            );
+            deny_update(ctx, READ_ONLY)?;
+        }
+
+        return Ok(false);
+    }
+    if !ctx.binds_clients() {
+        return Ok(true);
+    }
+
+    match structs {
+        Ok(structs) => check_clients(ctx, structs),
+        Err(err) => {
+            log::warn!(
+                "connection sends an invalid update to document `{}`, denied, err: {err}",
+                ctx.get_document_name()
+            );
+            deny_update(ctx, INVALID_UPDATE)?;
+            Ok(false)
+        }
+    }
+}
+
+/// Applies an update to the document, returns whether it changed the
+/// document. An update without new structs which deletes nothing new, as
+/// the `SyncStep2` of a synced client, is only acknowledged, an update
+/// y-octo fails to apply is denied as an invalid one. An update whose
+/// `structs` cannot be read counts as a change.
+fn apply_update<CTX: Context>(
+    ctx: &mut CTX,
+    update: &[u8],
+    structs: Option<&UpdateStructs>,
+) -> JwstCodecResult<bool> {
+    if let Some(structs) = structs {
+        if structs.within(&ctx.get_document().get_state_vector())
+            && ctx.get_delete_ranges().covers(&structs.deletes)
+        {
             ctx.unicast([message_header(ctx)?, write_sync_status(true)?].concat());
+            return Ok(false);
         }
     }
 
+    if let Err(err) = ctx
+        .get_document_mut()
+        .apply_update_from_binary(update.to_owned())
+    {
+        log::warn!(
+            "failed to apply update to document `{}`, denied, err: {err}",
+            ctx.get_document_name()
+        );
+        deny_update(ctx, INVALID_UPDATE)?;
+        return Ok(false);
+    }
+
+    match structs {
+        Some(structs) => ctx.get_delete_ranges_mut().extend(&structs.deletes),
+        None => {
+            // the deletes of the update are unknown, they are read back
+            let deleted = DeleteRanges::from_doc(ctx.get_document())?;
+            *ctx.get_delete_ranges_mut() = deleted;
+        }
+    }
+
+    Ok(true)
+}
+
+/// Tells the connection its update is denied and stays unsynced.
+fn deny_update<CTX: Context>(ctx: &CTX, reason: &str) -> JwstCodecResult<()> {
+    ctx.unicast([message_header(ctx)?, write_permission_denied(reason)?].concat());
+    ctx.unicast([message_header(ctx)?, write_sync_status(false)?].concat());
+
     Ok(())
 }
 
+/// Denies updates authored by Yjs clients bound to other connections, the
+/// structs the document already has are not authored by the update.
+fn check_clients<CTX: Context>(ctx: &mut CTX, structs: &UpdateStructs) -> JwstCodecResult<bool> {
+    let state = ctx.get_document().get_state_vector();
+    let clients = structs
+        .state()
+        .iter()
+        .filter(|(client, clock)| **clock > state.get(client))
+        .map(|(client, _)| *client)
+        .collect::<Vec<_>>();
+    if ctx.bind_clients(&clients) {
+        return Ok(true);
+    }
+
+    log::warn!(
+        "connection updates document `{}` with client ids of other connections, denied",
+        ctx.get_document_name()
+    );
+    deny_update(ctx, FOREIGN_CLIENT)?;
+
+    Ok(false)
+}
+
 fn handle_awareness_message<CTX: Context>(ctx: &mut CTX, message: &[u8]) -> JwstCodecResult<()> {
-    let update = read_awareness_update(message)?;
-
-    // callback
-    let values: Arc<Mutex<Vec<AwarenessEvent>>> = Arc::new(Mutex::new(Vec::new()));
-    let callback_values = Arc::clone(&values);
-    ctx.get_awareness_mut().on_update(move |_, event| {
-        let mut values = callback_values.lock().unwrap();
-        values.push(event);
-    });
-    ctx.get_awareness_mut().apply_update(update);
-
-    let states = values
-        .lock()
-        .unwrap()
-        .first()
-        .map(|value| value.get_updated(ctx.get_awareness().get_states()));
-    if let Some(states) = states {
-        let mut buffer = Vec::new();
-        write_sync_message(&mut buffer, &SyncMessage::Awareness(states))
-            .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))?;
-
-        ctx.broadcast([message_header(ctx)?, buffer].concat());
+    let (update, foreign): (Vec<_>, Vec<_>) = read_awareness_update(message)?
+        .into_iter()
+        .partition(|(client_id, _)| ctx.owns_awareness(*client_id));
+    if !foreign.is_empty() {
+        log::warn!(
+            "connection updates awareness clients of other connections in document `{}`, stripped: {:?}",
+            ctx.get_document_name(),
+            foreign
+                .iter()
+                .map(|(client_id, _)| *client_id)
+                .collect::<Vec<_>>()
+        );
     }
 
+    let changes = ctx.get_awareness_mut().apply_update(update);
+    if changes.is_empty() {
+        return Ok(());
+    }
+    ctx.on_awareness_update(&changes);
+
+    // only the clients whose clock advanced are announced
+    let buffer = ctx.get_awareness().encode(changes.client_ids())?;
+    ctx.broadcast([message_header(ctx)?, buffer].concat());
+
     Ok(())
 }
 
+/// Deletes the awareness states of the clients as y-protocols does for
+/// clients which went away, returns the deleted clients with the awareness
+/// message announcing the deletion, `None` when no state was alive.
+pub fn remove_awareness_states(
+    awareness: &mut Awareness,
+    client_ids: &[u64],
+) -> JwstCodecResult<Option<(AwarenessChanges, Vec<u8>)>> {
+    let changes = awareness.remove(client_ids);
+    if changes.is_empty() {
+        return Ok(None);
+    }
+
+    let buffer = awareness.encode(changes.client_ids())?;
+
+    Ok(Some((changes, buffer)))
+}
+
+/// Starts the sync with a joining connection as y-websocket does, clients
+/// which never send `SyncStep1` still receive the updates they miss. The
+/// full document is pushed right away with `push_state`.
+pub fn handle_join<CTX: Context>(ctx: &CTX, push_state: bool) -> JwstCodecResult<()> {
+    let doc = write_sync_step1(ctx.get_document())?;
+    ctx.unicast([message_header(ctx)?, doc].concat());
+
+    if push_state {
+        let update = write_sync_step2(ctx.get_document(), &StateVector::default())?;
+        ctx.unicast([message_header(ctx)?, update].concat());
+    }
+
+    handle_query_awareness(ctx)
+}
+
 pub fn handle_query_awareness<CTX: Context>(ctx: &CTX) -> JwstCodecResult<()> {
-    if ctx.get_awareness().get_states().is_empty() {
-        return Ok(());
+    if let Some(buffer) = ctx.get_awareness().encode_present()? {
+        ctx.unicast([message_header(ctx)?, buffer].concat());
     }
 
-    let states = ctx.get_awareness().get_states().clone();
-    let mut buffer = Vec::new();
-    write_sync_message(&mut buffer, &SyncMessage::Awareness(states))
+    Ok(())
+}
+
+pub fn read_document_name(message: &[u8]) -> JwstCodecResult<String> {
+    let (_, name) = read_var_string_inline(message)?;
+
+    Ok(name)
+}
+
+/// Reads the token of an `Auth` message, `None` for any other message.
+pub fn read_auth_token(message: &[u8]) -> JwstCodecResult<Option<String>> {
+    let (tail, _) = read_var_string_inline(message)?;
+    let (tail, typ) = read_var_u64_inline(tail)?;
+    if !matches!(typ.try_into()?, MessageType::Auth) {
+        return Ok(None);
+    }
+
+    let (tail, typ) = read_var_u64_inline(tail)?;
+    match typ.try_into()? {
+        AuthMessage::Token => {
+            let (_, token) = read_var_string_inline(tail)?;
+            Ok(Some(token))
+        }
+        _ => Err(JwstCodecError::InvalidStructType(
+            "unexpected auth message from client",
+        )),
+    }
+}
+
+/// Reads a frame of the client into a message carrying its document name.
+pub fn read_frame(framing: &Framing, frame: Vec<u8>) -> JwstCodecResult<Vec<u8>> {
+    match framing {
+        Framing::Hocuspocus => Ok(frame),
+        Framing::YWebsocket(name) => Ok([document_header(name)?, frame].concat()),
+    }
+}
+
+/// Writes a message of a document as a frame of the client, `None` when the
+/// framing has no such message, e.g. the stateless messages of Hocuspocus.
+pub fn write_frame(framing: &Framing, message: Vec<u8>) -> JwstCodecResult<Option<Vec<u8>>> {
+    if *framing == Framing::Hocuspocus {
+        return Ok(Some(message));
+    }
+
+    let (body, _) = read_var_string_inline(&message)?;
+    let (tail, typ) = read_var_u64_inline(body)?;
+    match typ.try_into()? {
+        MessageType::Sync | MessageType::Awareness | MessageType::QueryAwareness => {
+            Ok(Some(body.to_owned()))
+        }
+        MessageType::Auth => {
+            let (tail, typ) = read_var_u64_inline(tail)?;
+            match typ.try_into()? {
+                AuthMessage::PermissionDenied => {
+                    let (_, reason) = read_var_string_inline(tail)?;
+                    write_y_permission_denied(&reason).map(Some)
+                }
+                _ => Ok(None),
+            }
+        }
+        _ => Ok(None),
+    }
+}
+
+pub fn document_header(name: &str) -> JwstCodecResult<Vec<u8>> {
+    let mut head = Vec::with_capacity(9 + name.len());
+    write_var_string(&mut head, name)
         .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))?;
 
-    ctx.unicast([message_header(ctx)?, buffer].concat());
+    Ok(head)
+}
+
+/// Replies `SyncStatus` once the updates of the connection are durable, or
+/// failed to be stored.
+pub fn handle_sync_status<CTX: Context>(ctx: &CTX, saved: bool) -> JwstCodecResult<()> {
+    ctx.unicast([message_header(ctx)?, write_sync_status(saved)?].concat());
 
     Ok(())
 }
 
 #[inline]
 fn message_header<CTX: Context>(ctx: &CTX) -> JwstCodecResult<Vec<u8>> {
-    let mut head = Vec::with_capacity(9 + ctx.get_document_name().len());
-    write_var_string(&mut head, ctx.get_document_name())
-        .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))?;
-
-    Ok(head)
+    document_header(ctx.get_document_name())
 }
 
 #[inline]
@@ -31,6 +31,41 @@ impl From<DocMessage> for u64 {
     }
 }
 
+#[derive(Debug, Clone, Copy)]
+pub enum AuthMessage {
+    Token,
+    PermissionDenied,
+    Authenticated,
+    /// the token is about to expire, the client should send a new one
+    TokenRequired,
+}
+
+impl TryFrom<u64> for AuthMessage {
+    type Error = JwstCodecError;
+    fn try_from(value: u64) -> Result<Self, Self::Error> {
+        match value {
+            0 => Ok(Self::Token),
+            1 => Ok(Self::PermissionDenied),
+            2 => Ok(Self::Authenticated),
+            3 => Ok(Self::TokenRequired),
+            _ => Err(JwstCodecError::InvalidStructType(
+                "invalid auth message type",
+            )),
+        }
+    }
+}
+
+impl From<AuthMessage> for u64 {
+    fn from(value: AuthMessage) -> Self {
+        match value {
+            AuthMessage::Token => 0,
+            AuthMessage::PermissionDenied => 1,
+            AuthMessage::Authenticated => 2,
+            AuthMessage::TokenRequired => 3,
+        }
+    }
+}
+
 #[derive(Debug, Clone, Copy)]
 pub enum MessageType {
     Sync,
@@ -1,8 +1,19 @@
+mod auth;
 mod awareness;
+mod close;
 mod context;
 mod handler;
 mod message_type;
+mod stateless;
 mod sync;
+mod update;
 
+pub use auth::{write_authenticated, write_permission_denied, write_token_required};
+pub use awareness::{Awareness, AwarenessChanges};
+pub use close::write_close;
 pub use context::Context;
-pub use handler::{handle_message, handle_query_awareness};
+pub use handler::{
+    document_header, handle_join, handle_message, handle_sync_status, read_auth_token,
+    read_document_name, read_frame, remove_awareness_states, write_frame, Framing,
+};
+pub use update::{read_update_structs, DeleteRanges, StructParent, UpdateStruct};
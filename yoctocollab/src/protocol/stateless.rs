@@ -0,0 +1,20 @@
+use std::io;
+
+use y_octo::{write_var_string, write_var_u64, JwstCodecError, JwstCodecResult};
+
+use super::message_type::MessageType;
+
+pub fn write_stateless(payload: &str) -> JwstCodecResult<Vec<u8>> {
+    write_stateless_inline(payload)
+        .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))
+}
+
+#[inline]
+fn write_stateless_inline(payload: &str) -> Result<Vec<u8>, io::Error> {
+    let mut stateless = Vec::with_capacity(10 + payload.len());
+
+    write_var_u64(&mut stateless, MessageType::Stateless.into())?;
+    write_var_string(&mut stateless, payload)?;
+
+    Ok(stateless)
+}
@@ -46,7 +46,7 @@ pub fn write_sync_status(update_saved: bool) -> JwstCodecResult<Vec<u8>> {
 }
 
 pub fn write_sync_update(update: &[u8]) -> JwstCodecResult<Vec<u8>> {
-    write_sync_step2_inline(update)
+    write_sync_update_inline(update)
         .map_err(|err| JwstCodecError::InvalidWriteBuffer(err.to_string()))
 }
 
@@ -0,0 +1,413 @@
+use std::{collections::HashMap, ops::Range};
+
+use y_octo::{
+    Any, CrdtRead, CrdtReader, Doc, Id, JwstCodecError, JwstCodecResult, RawDecoder, StateVector,
+};
+
+const HAS_LEFT_ID: u8 = 0b1000_0000;
+const HAS_RIGHT_ID: u8 = 0b0100_0000;
+const HAS_PARENT_SUB: u8 = 0b0010_0000;
+
+const CONTENT_GC: u8 = 0;
+const CONTENT_DELETED: u8 = 1;
+const CONTENT_JSON: u8 = 2;
+const CONTENT_BINARY: u8 = 3;
+const CONTENT_STRING: u8 = 4;
+const CONTENT_EMBED: u8 = 5;
+const CONTENT_FORMAT: u8 = 6;
+const CONTENT_TYPE: u8 = 7;
+const CONTENT_ANY: u8 = 8;
+const CONTENT_DOC: u8 = 9;
+const CONTENT_SKIP: u8 = 10;
+
+const TYPE_XML_ELEMENT: u64 = 3;
+const TYPE_XML_HOOK: u64 = 5;
+
+/// Where a struct of an update is placed.
+#[derive(Debug, Clone)]
+pub enum StructParent {
+    /// the root type of the name
+    Root(String),
+    /// the type holding the item, or the origin next to it
+    Item(Id),
+    /// garbage collected or skipped structs
+    None,
+}
+
+/// A struct of an update, the decoder makes sure its clock with its length
+/// does not overflow.
+#[derive(Debug, Clone)]
+pub struct UpdateStruct {
+    pub id: Id,
+    pub len: u64,
+    pub parent: StructParent,
+}
+
+/// The structs and the delete set of a v1 update, without their content.
+#[derive(Debug, Default)]
+pub struct UpdateStructs {
+    pub structs: Vec<UpdateStruct>,
+    pub deletes: Vec<(u64, Range<u64>)>,
+}
+
+pub fn read_update_structs(update: &[u8]) -> JwstCodecResult<UpdateStructs> {
+    let mut decoder = RawDecoder::new(update.to_owned());
+    let mut structs = UpdateStructs::default();
+
+    let num_of_clients = decoder.read_var_u64()?;
+    for _ in 0..num_of_clients {
+        let num_of_structs = decoder.read_var_u64()?;
+        let client = decoder.read_var_u64()?;
+        let mut clock = decoder.read_var_u64()?;
+
+        for _ in 0..num_of_structs {
+            let (len, parent) = read_struct(&mut decoder)?;
+            structs.structs.push(UpdateStruct {
+                id: Id::new(client, clock),
+                len,
+                parent,
+            });
+            clock = end_clock(clock, len)?;
+        }
+    }
+
+    let num_of_clients = decoder.read_var_u64()?;
+    for _ in 0..num_of_clients {
+        let client = decoder.read_var_u64()?;
+        let num_of_ranges = decoder.read_var_u64()?;
+        for _ in 0..num_of_ranges {
+            let clock = decoder.read_var_u64()?;
+            let len = decoder.read_var_u64()?;
+            structs
+                .deletes
+                .push((client, clock..end_clock(clock, len)?));
+        }
+    }
+
+    Ok(structs)
+}
+
+/// Adds a length read from an update to a clock, a crafted length must not
+/// overflow it.
+fn end_clock(clock: u64, len: u64) -> JwstCodecResult<u64> {
+    clock
+        .checked_add(len)
+        .ok_or(JwstCodecError::InvalidStructType("clock overflow"))
+}
+
+impl UpdateStructs {
+    /// The clock each client reaches with the structs.
+    pub fn state(&self) -> StateVector {
+        let mut state = StateVector::default();
+        for update_struct in &self.structs {
+            state.set_max(
+                update_struct.id.client,
+                update_struct.id.clock + update_struct.len,
+            );
+        }
+
+        state
+    }
+
+    /// Whether the document of the state has all the structs.
+    pub fn within(&self, state: &StateVector) -> bool {
+        self.structs.iter().all(|update_struct| {
+            update_struct.id.clock + update_struct.len <= state.get(&update_struct.id.client)
+        })
+    }
+}
+
+/// The deleted clocks of a document by client, sorted and merged. They are
+/// kept next to the document and extended with every applied update, the
+/// deletes of an update are checked without walking the whole document.
+#[derive(Debug, Default)]
+pub struct DeleteRanges {
+    ranges: HashMap<u64, Vec<Range<u64>>>,
+}
+
+impl DeleteRanges {
+    /// Reads the delete set of the document, a diff against its own state
+    /// vector carries only the delete set.
+    pub fn from_doc(doc: &Doc) -> JwstCodecResult<Self> {
+        let diff = doc.encode_state_as_update_v1(&doc.get_state_vector())?;
+        let mut deleted = Self::default();
+        deleted.extend(&read_update_structs(&diff)?.deletes);
+
+        Ok(deleted)
+    }
+
+    /// Whether all the deletes are deleted already.
+    pub fn covers(&self, deletes: &[(u64, Range<u64>)]) -> bool {
+        deletes.iter().all(|(client, range)| {
+            let Some(ranges) = self.ranges.get(client) else {
+                return range.is_empty();
+            };
+            // the last range starting at or before the range
+            let i = ranges.partition_point(|deleted| deleted.start <= range.start);
+            range.is_empty() || (i > 0 && ranges[i - 1].end >= range.end)
+        })
+    }
+
+    pub fn extend(&mut self, deletes: &[(u64, Range<u64>)]) {
+        for (client, range) in deletes {
+            if range.is_empty() {
+                continue;
+            }
+
+            let ranges = self.ranges.entry(*client).or_default();
+            // the ranges overlapping or touching the range are merged into it
+            let start = ranges.partition_point(|deleted| deleted.end < range.start);
+            let end = ranges.partition_point(|deleted| deleted.start <= range.end);
+            if start == end {
+                ranges.insert(start, range.clone());
+            } else {
+                let merged =
+                    ranges[start].start.min(range.start)..ranges[end - 1].end.max(range.end);
+                ranges.splice(start..end, [merged]);
+            }
+        }
+    }
+}
+
+/// Whether the document already holds everything of a v1 update, the structs
+/// within its state vector and the deletions within `deleted`, as
+/// `snapshotContainsUpdate` of Hocuspocus. Yjs puts the full delete set into
+/// every `SyncStep2`, so such an update changes nothing. `structs` are the
+/// structs read from the update.
+pub fn contains_update(
+    doc: &Doc,
+    deleted: &DeleteRanges,
+    update: &[u8],
+    structs: &UpdateStructs,
+) -> JwstCodecResult<bool> {
+    let state = doc.get_state_vector();
+    if !structs.within(&state) {
+        return Ok(false);
+    }
+    if deleted.covers(&structs.deletes) {
+        return Ok(true);
+    }
+
+    // y-octo does not record the deletion of items inside a deleted type,
+    // which Yjs lists in its delete set, so the update is tried on a copy
+    let mut copy = Doc::new_from_binary(doc.encode_update_v1()?)?;
+    let diff = copy.encode_state_as_update_v1(&state)?;
+    copy.apply_update_from_binary(update.to_owned())?;
+
+    Ok(copy.get_state_vector() == state && copy.encode_state_as_update_v1(&state)? == diff)
+}
+
+/// Reads a struct without its content, returns how many clocks it takes
+/// with where it is placed.
+fn read_struct(decoder: &mut RawDecoder) -> JwstCodecResult<(u64, StructParent)> {
+    let info = decoder.read_info()?;
+    let content = info & 0b11111;
+    if matches!(content, CONTENT_GC | CONTENT_SKIP) {
+        return Ok((decoder.read_var_u64()?, StructParent::None));
+    }
+
+    let left = (info & HAS_LEFT_ID != 0)
+        .then(|| decoder.read_item_id())
+        .transpose()?;
+    let right = (info & HAS_RIGHT_ID != 0)
+        .then(|| decoder.read_item_id())
+        .transpose()?;
+    // the parent is only written for items without origins, which share the
+    // parent of their origins
+    let parent = match left.or(right) {
+        Some(origin) => StructParent::Item(origin),
+        None => {
+            let parent = if decoder.read_var_u64()? == 1 {
+                StructParent::Root(decoder.read_var_string()?)
+            } else {
+                StructParent::Item(decoder.read_item_id()?)
+            };
+            if info & HAS_PARENT_SUB != 0 {
+                decoder.read_var_string()?;
+            }
+            parent
+        }
+    };
+
+    let len = read_content_len(decoder, content)?;
+
+    Ok((len, parent))
+}
+
+/// Skips the content of an item, returns how many clocks it takes.
+fn read_content_len(decoder: &mut RawDecoder, content: u8) -> JwstCodecResult<u64> {
+    match content {
+        CONTENT_DELETED => decoder.read_var_u64(),
+        CONTENT_JSON => {
+            let len = decoder.read_var_u64()?;
+            for _ in 0..len {
+                decoder.read_var_string()?;
+            }
+            Ok(len)
+        }
+        CONTENT_BINARY => decoder.read_var_buffer().map(|_| 1),
+        // the clock of a string counts its utf-16 code units
+        CONTENT_STRING => decoder
+            .read_var_string()
+            .map(|string| string.encode_utf16().count() as u64),
+        CONTENT_EMBED => decoder.read_var_string().map(|_| 1),
+        CONTENT_FORMAT => {
+            decoder.read_var_string()?;
+            decoder.read_var_string()?;
+            Ok(1)
+        }
+        CONTENT_TYPE => {
+            let type_ref = decoder.read_var_u64()?;
+            if matches!(type_ref, TYPE_XML_ELEMENT | TYPE_XML_HOOK) {
+                decoder.read_var_string()?;
+            }
+            Ok(1)
+        }
+        CONTENT_ANY => {
+            let len = decoder.read_var_u64()?;
+            for _ in 0..len {
+                Any::read(decoder)?;
+            }
+            Ok(len)
+        }
+        CONTENT_DOC => {
+            decoder.read_var_string()?;
+            Any::read(decoder)?;
+            Ok(1)
+        }
+        _ => Err(JwstCodecError::InvalidStructType(
+            "unknown content of struct",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// documents encoded by Yjs, taken from the test fixtures of y-octo, with
+    /// their number of structs
+    const FIXTURES: [(&[u8], usize); 3] = [
+        (include_bytes!("fixtures/basic.bin"), 188),
+        (include_bytes!("fixtures/database.bin"), 149),
+        (include_bytes!("fixtures/with-subdoc.bin"), 30),
+    ];
+
+    /// `ydoc.getText('t').insert(0, 'hello')` by the Yjs client 1
+    const TEXT_HELLO: [u8; 15] = [1, 1, 1, 0, 4, 1, 1, 116, 5, 104, 101, 108, 108, 111, 0];
+    /// `ydoc.getText('t').insert(0, 'a😀b')` by the Yjs client 1
+    const TEXT_EMOJI: [u8; 16] = [1, 1, 1, 0, 4, 1, 1, 116, 6, 97, 240, 159, 152, 128, 98, 0];
+
+    fn contains(doc: &Doc, update: &[u8]) -> bool {
+        let deleted = DeleteRanges::from_doc(doc).unwrap();
+        contains_update(doc, &deleted, update, &read_update_structs(update).unwrap()).unwrap()
+    }
+
+    #[test]
+    fn read_fixtures() {
+        for (fixture, structs) in FIXTURES {
+            let update = read_update_structs(fixture).unwrap();
+            assert_eq!(update.structs.len(), structs);
+
+            let doc = Doc::new_from_binary(fixture.to_vec()).unwrap();
+            let state = read_update_structs(fixture).unwrap().state();
+            for (client, clock) in doc.get_state_vector().iter() {
+                assert_eq!(state.get(client), *clock);
+            }
+        }
+    }
+
+    #[test]
+    fn read_root_parent() {
+        let update = read_update_structs(&TEXT_HELLO).unwrap();
+        assert_eq!(update.structs.len(), 1);
+
+        let update_struct = &update.structs[0];
+        assert_eq!(update_struct.id, Id::new(1, 0));
+        assert_eq!(update_struct.len, 5);
+        assert!(matches!(&update_struct.parent, StructParent::Root(name) if name == "t"));
+        assert!(update.deletes.is_empty());
+    }
+
+    #[test]
+    fn read_string_len_in_utf16() {
+        let state = read_update_structs(&TEXT_EMOJI).unwrap().state();
+        assert_eq!(state.get(&1), 4);
+    }
+
+    #[test]
+    fn read_overflowing_clock() {
+        const MAX_LEN: [u8; 10] = [255, 255, 255, 255, 255, 255, 255, 255, 255, 1];
+
+        // a deleted struct of length `u64::MAX` followed by another struct
+        let update = [
+            &[1, 2, 1, 0, 1, 1, 1, 116][..],
+            &MAX_LEN,
+            &[1, 1, 1, 116, 1, 0],
+        ]
+        .concat();
+        assert!(read_update_structs(&update).is_err());
+
+        // a delete range of length `u64::MAX` starting after the clock 0
+        let update = [&[0, 1, 1, 1, 1][..], &MAX_LEN].concat();
+        assert!(read_update_structs(&update).is_err());
+    }
+
+    #[test]
+    fn read_unknown_content() {
+        let mut update = TEXT_HELLO;
+        update[4] = 11;
+        assert!(read_update_structs(&update).is_err());
+    }
+
+    #[test]
+    fn contains_fixtures() {
+        for (fixture, _) in FIXTURES {
+            let doc = Doc::new_from_binary(fixture.to_vec()).unwrap();
+            assert!(contains(&doc, fixture));
+            assert!(!contains(&Doc::default(), fixture));
+        }
+    }
+
+    #[test]
+    fn contains_delete_set() {
+        let doc = Doc::with_client(1);
+        let mut text = doc.get_or_create_text("t").unwrap();
+        text.insert(0, "hello").unwrap();
+        text.remove(1, 2).unwrap();
+        assert_eq!(text.to_string(), "hlo");
+
+        // what a synced client answers to `SyncStep1`
+        let answer = doc
+            .encode_state_as_update_v1(&doc.get_state_vector())
+            .unwrap();
+        assert!(!read_update_structs(&answer).unwrap().deletes.is_empty());
+        assert!(contains(&doc, &answer));
+
+        let other = Doc::new_from_binary(doc.encode_update_v1().unwrap()).unwrap();
+        other.get_or_create_text("t").unwrap().remove(2, 1).unwrap();
+        let delete = other
+            .encode_state_as_update_v1(&doc.get_state_vector())
+            .unwrap();
+        assert!(!contains(&doc, &delete));
+    }
+
+    #[test]
+    fn delete_ranges() {
+        let mut deleted = DeleteRanges::default();
+        deleted.extend(&[(1, 5..7), (1, 2..3), (1, 3..4), (2, 0..1)]);
+        assert_eq!(deleted.ranges[&1], [2..4, 5..7]);
+
+        assert!(deleted.covers(&[(1, 2..4), (1, 5..6), (2, 0..1), (3, 0..0)]));
+        assert!(!deleted.covers(&[(1, 3..6)]));
+        assert!(!deleted.covers(&[(1, 0..1)]));
+        assert!(!deleted.covers(&[(3, 0..1)]));
+
+        deleted.extend(&[(1, 4..5)]);
+        assert!(deleted.covers(&[(1, 2..7)]));
+        assert_eq!(deleted.ranges[&1].len(), 1);
+        deleted.extend(&[(1, 0..10)]);
+        assert!(deleted.covers(&[(1, 0..10)]));
+        assert_eq!(deleted.ranges[&1].len(), 1);
+    }
+}
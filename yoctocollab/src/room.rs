@@ -1,15 +1,43 @@
-use tokio::sync::mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender};
-use tokio_tungstenite::tungstenite::Message;
-use y_octo::Doc;
+use std::{collections::HashMap, sync::Arc, time::SystemTime};
 
-use crate::doc::Document;
+use tokio::{
+    sync::{
+        mpsc::{unbounded_channel, UnboundedReceiver, UnboundedSender, WeakUnboundedSender},
+        RwLock,
+    },
+    time::{sleep, sleep_until, Instant},
+};
+use tokio_tungstenite::tungstenite::{
+    protocol::{frame::coding::CloseCode, CloseFrame},
+    Message,
+};
+use y_octo::{Doc, StateVector};
+
+use crate::{
+    audit::{AuditRecord, AuditSink, RootIndex},
+    auth::Permission,
+    config::RoomConfig,
+    doc::Document,
+    extension::{
+        AwarenessPayload, ChangePayload, DestroyPayload, DisconnectPayload, Extensions,
+        MessagePayload, Reject, StoreDocumentPayload,
+    },
+    storage::DocumentStore,
+};
+
+pub(crate) const ROOM_UNAVAILABLE: &str = "room_unavailable";
 
 enum RoomMessage {
-    Join(u64, UnboundedSender<Message>),
+    Join(u64, UnboundedSender<Message>, Permission, Option<String>),
     Message(u64, Vec<u8>),
+    Permission(u64, Permission),
     Leave(u64),
 }
 
+/// The rooms of the server by their document name, joins are sent while
+/// holding the read lock so that a room can deregister itself atomically.
+pub(crate) type Rooms = Arc<RwLock<HashMap<String, RoomCommand>>>;
+
 #[derive(Clone)]
 pub struct RoomCommand {
     cmd: UnboundedSender<RoomMessage>,
@@ -20,83 +48,614 @@ impl RoomCommand {
         Self { cmd }
     }
 
+    /// Whether the command is the one of the room owning the weak sender.
+    fn is(&self, room: &WeakUnboundedSender<RoomMessage>) -> bool {
+        room.upgrade()
+            .is_some_and(|cmd| cmd.same_channel(&self.cmd))
+    }
+
     pub(super) fn join(
         &self,
         connection_id: u64,
         room_outgoing: UnboundedSender<Message>,
+        permission: Permission,
+        user_id: Option<String>,
     ) -> Result<(), ()> {
-        match self
-            .cmd
-            .send(RoomMessage::Join(connection_id, room_outgoing))
-        {
+        match self.cmd.send(RoomMessage::Join(
+            connection_id,
+            room_outgoing,
+            permission,
+            user_id,
+        )) {
             Ok(()) => Ok(()),
             Err(err) => {
                 log::error!("join room failed, err: {err}");
-                return Err(());
+                Err(())
+            }
+        }
+    }
+
+    pub(super) fn message(&self, connection_id: u64, message: Vec<u8>) -> Result<(), ()> {
+        match self.cmd.send(RoomMessage::Message(connection_id, message)) {
+            Ok(()) => Ok(()),
+            Err(err) => {
+                log::error!("send room message failed, err: {err}");
+                Err(())
             }
         }
     }
+
+    pub(super) fn permission(&self, connection_id: u64, permission: Permission) -> Result<(), ()> {
+        self.cmd
+            .send(RoomMessage::Permission(connection_id, permission))
+            .map_err(|_| ())
+    }
+
+    pub(super) fn leave(&self, connection_id: u64) -> Result<(), ()> {
+        self.cmd
+            .send(RoomMessage::Leave(connection_id))
+            .map_err(|_| ())
+    }
+}
+
+/// An update applied to the document but not yet durable.
+struct PendingUpdate {
+    cid: u64,
+    editor: String,
+}
+
+/// Updates applied to the document but not yet durable.
+#[derive(Default)]
+struct PendingUpdates {
+    updates: Vec<PendingUpdate>,
+    first_at: Option<Instant>,
+    last_at: Option<Instant>,
+}
+
+impl PendingUpdates {
+    fn push(&mut self, updates: Vec<PendingUpdate>) {
+        if updates.is_empty() {
+            return;
+        }
+
+        let now = Instant::now();
+        self.first_at.get_or_insert(now);
+        self.last_at = Some(now);
+        self.updates.extend(updates);
+    }
+
+    /// The store waits `debounce` after the latest update, but never longer
+    /// than `max_debounce` after the first pending one.
+    fn deadline(&self, config: &RoomConfig) -> Option<Instant> {
+        let first_at = self.first_at?;
+        let last_at = self.last_at?;
+
+        Some((last_at + config.debounce).min(first_at + config.max_debounce))
+    }
+
+    fn take(&mut self) -> Vec<PendingUpdate> {
+        self.first_at = None;
+        self.last_at = None;
+
+        std::mem::take(&mut self.updates)
+    }
 }
 
 pub struct Room {
     document: Document,
+    config: RoomConfig,
+    store: Arc<dyn DocumentStore>,
+    extensions: Arc<Extensions>,
+    audit: Option<Arc<dyn AuditSink>>,
+    /// the root types of the structs, only kept for the audit
+    roots: RootIndex,
+    pending: PendingUpdates,
+    /// the state vector of the document when it was last stored, the pending
+    /// updates are stored as one diff against it
+    stored_state: StateVector,
     receiver: UnboundedReceiver<RoomMessage>,
+    /// the document is not loaded, it is loaded by the next join
+    hibernated: bool,
+
+    rooms: Rooms,
+    /// tells the own entry of `rooms` apart, a strong sender would keep the
+    /// receiver open once every command is dropped
+    command: WeakUnboundedSender<RoomMessage>,
 }
 
 impl Room {
-    fn new(name: String, doc: Doc, receiver: UnboundedReceiver<RoomMessage>) -> Self {
-        let document = Document::new(name, doc);
+    fn new(
+        name: String,
+        config: RoomConfig,
+        store: Arc<dyn DocumentStore>,
+        extensions: Arc<Extensions>,
+        audit: Option<Arc<dyn AuditSink>>,
+        rooms: Rooms,
+    ) -> (Self, RoomCommand) {
+        let (sender, receiver) = unbounded_channel();
+
+        let document = Document::new(name, Doc::default(), extensions.clone(), false, false);
+
+        let room = Self {
+            document,
+            config,
+            store,
+            extensions,
+            audit,
+            roots: RootIndex::default(),
+            pending: PendingUpdates::default(),
+            stored_state: StateVector::default(),
+            receiver,
+            hibernated: true,
 
-        Self { document, receiver }
+            rooms,
+            command: sender.downgrade(),
+        };
+
+        (room, RoomCommand::new(sender))
+    }
+
+    /// Replaces the document of the room with a loaded one.
+    fn open(&mut self, doc: Doc) {
+        let name = self.document.get_name().to_owned();
+        self.document = Document::new(
+            name,
+            doc,
+            self.extensions.clone(),
+            self.config.bind_client_ids,
+            self.config.push_state,
+        );
+        self.stored_state = self.document.state_vector();
+
+        self.roots = RootIndex::default();
+        if self.audit.is_some() {
+            if let Err(err) = self
+                .document
+                .encode_state()
+                .and_then(|state| self.roots.apply(&state))
+            {
+                log::error!(
+                    "index document `{}` failed, err: {err}",
+                    self.document.get_name()
+                );
+            }
+        }
+    }
+
+    /// Loads the document for the first join, the joins and messages sent
+    /// meanwhile wait in the queue. Returns whether the document is loaded.
+    async fn start(&mut self) -> bool {
+        match self.wake().await {
+            Ok(()) => true,
+            Err(reject) => {
+                self.abort(reject.reason()).await;
+                false
+            }
+        }
     }
 
     async fn run(&mut self) {
-        while let Some(msg) = self.receiver.recv().await {
+        loop {
+            let idle = !self.hibernated && self.document.is_connection_empty();
+            let deadline = self.pending.deadline(&self.config);
+            let awareness_deadline = self
+                .document
+                .awareness_deadline(self.config.awareness_timeout);
+
+            // not biased, a steady stream of messages must not starve the
+            // deadlines
+            tokio::select! {
+                msg = self.receiver.recv() => match msg {
+                    Some(msg) => {
+                        self.handle_message(msg).await;
+                        self.on_awareness_update().await;
+                    }
+                    // every command is dropped, the server is gone
+                    None => {
+                        if !self.hibernated {
+                            self.persist().await;
+                        }
+                        break;
+                    }
+                },
+                _ = sleep_until(deadline.unwrap_or_else(Instant::now)), if deadline.is_some() => {
+                    self.persist_updates().await;
+                }
+                _ = sleep_until(awareness_deadline.unwrap_or_else(Instant::now)), if awareness_deadline.is_some() => {
+                    self.document.expire_awareness(self.config.awareness_timeout);
+                    self.on_awareness_update().await;
+                }
+                // an empty room lingers for `idle_timeout` waiting for a new join
+                _ = sleep(self.config.idle_timeout), if idle => {
+                    if self.config.hibernate_timeout.is_zero() {
+                        if self.teardown().await {
+                            break;
+                        }
+                    } else {
+                        self.hibernate().await;
+                    }
+                }
+                _ = sleep(self.config.hibernate_timeout), if self.hibernated => {
+                    if self.teardown().await {
+                        break;
+                    }
+                }
+            }
+        }
+    }
+
+    /// Stores the document and unloads it, the room stays registered and
+    /// loads the document again on the next join.
+    async fn hibernate(&mut self) {
+        if !self.persist().await {
+            return;
+        }
+
+        self.open(Doc::default());
+        self.hibernated = true;
+    }
+
+    /// Loads the document of a new or hibernated room.
+    async fn wake(&mut self) -> Result<(), Reject> {
+        if !self.hibernated {
+            return Ok(());
+        }
+
+        let doc = load_document(
+            self.store.as_ref(),
+            &self.extensions,
+            self.document.get_name(),
+        )
+        .await
+        .inspect_err(|reject| {
+            log::error!(
+                "load room `{}` failed, reason: {reject}",
+                self.document.get_name()
+            )
+        })?;
+        self.open(doc);
+        self.hibernated = false;
+
+        Ok(())
+    }
+
+    /// Removes the room as its document could not be loaded, the queued
+    /// joins are refused with the reason.
+    async fn abort(&mut self, reason: &str) {
+        let mut rooms = self.rooms.write().await;
+        let name = self.document.get_name();
+        if rooms.get(name).is_some_and(|room| room.is(&self.command)) {
+            rooms.remove(name);
+        }
+        drop(rooms);
+
+        // no join can be queued after the room is removed
+        while let Ok(msg) = self.receiver.try_recv() {
+            if let RoomMessage::Join(_, connection, _, _) = msg {
+                reject_join(&connection, reason);
+            }
+        }
+    }
+
+    /// Stores the document and removes the room from the server, returns
+    /// `false` when the room has to keep running as the document could not be
+    /// stored or a connection joined meanwhile.
+    async fn teardown(&mut self) -> bool {
+        // a hibernated document is already stored
+        if !self.hibernated && !self.persist().await {
+            return false;
+        }
+
+        let mut rooms = self.rooms.write().await;
+        // a join sent before the lock was taken is still queued
+        if let Ok(msg) = self.receiver.try_recv() {
+            drop(rooms);
             self.handle_message(msg).await;
+            return false;
+        }
+
+        let name = self.document.get_name();
+        if rooms.get(name).is_some_and(|room| room.is(&self.command)) {
+            rooms.remove(name);
+        }
 
-            if self.document.is_connection_empty() {
-                break;
+        true
+    }
+
+    /// Stores the pending updates as a single update, attributed to the
+    /// editor of the latest one.
+    async fn persist_updates(&mut self) {
+        let pending = self.pending.take();
+        let Some(PendingUpdate { editor, .. }) = pending.last() else {
+            return;
+        };
+
+        let state = self.document.state_vector();
+        let result = match self.document.encode_diff(&self.stored_state) {
+            Ok(update) => self
+                .store
+                .store_update(self.document.get_name(), &update, editor)
+                .await
+                .map_err(|err| err.to_string()),
+            Err(err) => Err(err.to_string()),
+        };
+        let saved = match result {
+            Ok(()) => {
+                self.stored_state = state;
+                true
+            }
+            Err(err) => {
+                log::error!(
+                    "store updates of `{}` failed, err: {err}",
+                    self.document.get_name()
+                );
+                false
+            }
+        };
+
+        if saved {
+            self.on_store_document().await;
+        }
+        self.sync_status(pending.iter().map(|pending| (pending.cid, saved)).collect());
+    }
+
+    /// Stores the full state of the document, returns whether it is stored.
+    async fn persist(&mut self) -> bool {
+        // the full state supersedes the pending updates
+        let cids = self
+            .pending
+            .take()
+            .into_iter()
+            .map(|pending| pending.cid)
+            .collect::<Vec<_>>();
+
+        let state_vector = self.document.state_vector();
+        let saved = match self.document.encode_state() {
+            Ok(state) => match self.store.store(self.document.get_name(), &state).await {
+                Ok(()) => {
+                    self.stored_state = state_vector;
+                    true
+                }
+                Err(err) => {
+                    log::error!(
+                        "store document `{}` failed, err: {err}",
+                        self.document.get_name()
+                    );
+                    false
+                }
+            },
+            Err(err) => {
+                log::error!(
+                    "encode document `{}` failed, err: {err}",
+                    self.document.get_name()
+                );
+                false
             }
+        };
+
+        if saved {
+            self.on_store_document().await;
+        }
+        self.sync_status(cids.into_iter().map(|cid| (cid, saved)).collect());
+
+        saved
+    }
+
+    async fn on_store_document(&self) {
+        let payload = StoreDocumentPayload {
+            document_name: self.document.get_name(),
+        };
+        self.extensions.on_store_document(payload).await;
+    }
+
+    async fn disconnect(&mut self, cid: u64) {
+        if self.document.disconnect(cid) {
+            self.on_disconnect(cid).await;
+        }
+    }
+
+    async fn on_disconnect(&self, cid: u64) {
+        let payload = DisconnectPayload {
+            document_name: self.document.get_name(),
+            connection_id: cid,
+        };
+        self.extensions.on_disconnect(payload).await;
+    }
+
+    async fn record(&mut self, cid: u64, update: &[u8]) {
+        let Some(audit) = &self.audit else {
+            return;
+        };
+
+        let root_types = self.roots.apply(update).unwrap_or_else(|err| {
+            log::error!("read root types of update failed, err: {err}");
+            Vec::new()
+        });
+        let record = AuditRecord {
+            document_name: self.document.get_name().to_owned(),
+            connection_id: cid,
+            user_id: self.document.user_id(cid).map(str::to_owned),
+            timestamp: SystemTime::now(),
+            size: update.len(),
+            root_types,
+        };
+        if let Err(err) = audit.record(&record).await {
+            log::error!(
+                "record update of `{}` failed, err: {err}",
+                self.document.get_name()
+            );
+        }
+    }
+
+    /// Who edits through the connection, taken when the update is applied as
+    /// the connection may have left once it is stored.
+    fn editor(&self, cid: u64) -> String {
+        self.document
+            .user_id(cid)
+            .map(str::to_owned)
+            .unwrap_or_else(|| cid.to_string())
+    }
+
+    async fn on_awareness_update(&mut self) {
+        for (cid, changes) in self.document.take_awareness_changes() {
+            let payload = AwarenessPayload {
+                document_name: self.document.get_name(),
+                connection_id: cid,
+                added: &changes.added,
+                updated: &changes.updated,
+                removed: &changes.removed,
+            };
+            self.extensions.on_awareness_update(payload).await;
         }
+    }
 
-        // TODO callback
+    /// Replies one `SyncStatus` per update, the provider counts its unsynced
+    /// updates one by one.
+    fn sync_status(&mut self, saved: Vec<(u64, bool)>) {
+        for (cid, saved) in saved {
+            if let Err(err) = self.document.sync_status(cid, saved) {
+                log::error!("send sync status failed, err: {err}");
+            }
+        }
     }
 
     async fn handle_message(&mut self, msg: RoomMessage) {
         match msg {
-            RoomMessage::Join(cid, connection) => {
-                if let Err(err) = self.document.connect(cid, connection) {
+            RoomMessage::Join(cid, connection, permission, user_id) => {
+                if self.config.max_connections.is_some_and(|max_connections| {
+                    self.document.connection_count() >= max_connections
+                }) {
+                    log::warn!("room `{}` is full", self.document.get_name());
+                    reject_join(&connection, "room_full");
+                    return;
+                }
+
+                if let Err(reject) = self.wake().await {
+                    reject_join(&connection, reject.reason());
+                    return;
+                }
+
+                if let Err(err) = self.document.connect(cid, connection, permission, user_id) {
                     log::error!("join room failed, err: {err}");
-                    self.document.disconnect(cid);
+                    self.disconnect(cid).await;
                 }
             }
 
-            RoomMessage::Leave(cid) => self.document.disconnect(cid),
+            RoomMessage::Permission(cid, permission) => {
+                self.document.set_permission(cid, permission);
+            }
+
+            RoomMessage::Leave(cid) => self.disconnect(cid).await,
 
             RoomMessage::Message(cid, message) => {
+                if !self.document.is_connected(cid) {
+                    return;
+                }
+
+                let payload = MessagePayload {
+                    document_name: self.document.get_name(),
+                    connection_id: cid,
+                    message: &message,
+                };
+                if let Err(reject) = self.extensions.on_message(payload).await {
+                    log::warn!("reject message, reason: {reject}");
+                    if self.document.close(cid, reject.reason()) {
+                        self.on_disconnect(cid).await;
+                    }
+                    return;
+                }
+
                 if let Err(err) = self.document.handle_message(cid, &message).await {
                     log::error!("handle message failed, err: {err}");
                     self.document.disconnect(cid);
                 }
+                if !self.document.is_connected(cid) {
+                    self.on_disconnect(cid).await;
+                }
+
+                let updates = self.document.take_updates();
+                for (cid, update) in updates.iter() {
+                    let payload = ChangePayload {
+                        document_name: self.document.get_name(),
+                        connection_id: *cid,
+                        update,
+                    };
+                    self.extensions.on_change(payload).await;
+                    self.record(*cid, update).await;
+                }
+                let updates = updates
+                    .into_iter()
+                    .map(|(cid, _)| PendingUpdate {
+                        cid,
+                        editor: self.editor(cid),
+                    })
+                    .collect();
+                self.pending.push(updates);
+                if self.config.debounce.is_zero() {
+                    self.persist_updates().await;
+                }
             }
         }
     }
 
-    pub fn create<F: FnOnce(&str) + Send + 'static>(
+    /// Spawns the room of the document, the room removes itself from `rooms`
+    /// once it is destroyed.
+    pub fn create(
         name: String,
-        doc: Doc,
-        on_destory: F,
+        config: RoomConfig,
+        store: Arc<dyn DocumentStore>,
+        extensions: Arc<Extensions>,
+        audit: Option<Arc<dyn AuditSink>>,
+        rooms: Rooms,
     ) -> RoomCommand {
-        let (sender, receiver) = unbounded_channel();
-
-        tokio::spawn(async {
-            let mut room = Self::new(name, doc, receiver);
+        let (mut room, command) = Self::new(name, config, store, extensions, audit, rooms);
 
+        tokio::spawn(async move {
+            if !room.start().await {
+                return;
+            }
             room.run().await;
 
-            on_destory(room.document.get_name());
+            let payload = DestroyPayload {
+                document_name: room.document.get_name(),
+            };
+            room.extensions.on_destroy(payload).await;
         });
 
-        RoomCommand::new(sender)
+        command
     }
 }
+
+fn reject_join(connection: &UnboundedSender<Message>, reason: &str) {
+    let frame = CloseFrame {
+        code: CloseCode::Again,
+        reason: reason.to_owned().into(),
+    };
+    if connection.send(Message::Close(Some(frame))).is_err() {
+        log::error!("reject connection failed");
+    }
+}
+
+/// Restores the document from the store, extensions may fill it afterwards.
+async fn load_document(
+    store: &dyn DocumentStore,
+    extensions: &Extensions,
+    doc_name: &str,
+) -> Result<Doc, Reject> {
+    let updates = store.load(doc_name).await.map_err(|err| {
+        log::error!("load document `{doc_name}` failed, err: {err}");
+        Reject::new(ROOM_UNAVAILABLE)
+    })?;
+
+    let mut doc = Doc::default();
+    for update in updates {
+        doc.apply_update_from_binary(update).map_err(|err| {
+            log::error!("apply stored update of `{doc_name}` failed, err: {err}");
+            Reject::new(ROOM_UNAVAILABLE)
+        })?;
+    }
+
+    extensions.on_load_document(doc_name, &mut doc).await?;
+
+    Ok(doc)
+}
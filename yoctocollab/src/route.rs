@@ -0,0 +1,257 @@
+use std::fmt;
+
+use tokio_tungstenite::tungstenite::http::{StatusCode, Uri};
+
+const DOCUMENT_PATH_PREFIX: &str = "/doc";
+const DOCUMENT_QUERY_KEY: &str = "document";
+const MAX_DOCUMENT_NAME_LEN: usize = 255;
+
+#[derive(Debug)]
+pub enum Error {
+    NotFound(String),
+    Missing,
+    Malformed(String),
+}
+
+impl Error {
+    pub(crate) fn status(&self) -> StatusCode {
+        match self {
+            Self::NotFound(_) => StatusCode::NOT_FOUND,
+            Self::Missing | Self::Malformed(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::NotFound(path) => write!(f, "not found path `{path}`"),
+            Self::Missing => f.write_str("missing document name"),
+            Self::Malformed(reason) => write!(f, "malformed document name, {reason}"),
+        }
+    }
+}
+
+/// Resolves the document name of a websocket request.
+///
+/// The name is taken from `/doc/<name>`, or from the `document` query
+/// parameter when the request targets `/` or `/doc`. A request without any
+/// document name opens a multiplexed connection, which attaches to documents
+/// on demand.
+pub(crate) fn document_name(uri: &Uri) -> Result<Option<String>, Error> {
+    let path = uri.path();
+
+    if let Some(name) = path
+        .strip_prefix(DOCUMENT_PATH_PREFIX)
+        .and_then(|tail| tail.strip_prefix('/'))
+        .filter(|name| !name.is_empty())
+    {
+        return normalize(&percent_decode(name, false)?).map(Some);
+    }
+
+    if !matches!(path, "" | "/" | "/doc" | "/doc/") {
+        return Err(Error::NotFound(path.to_owned()));
+    }
+
+    query_param(uri, DOCUMENT_QUERY_KEY)?
+        .map(|name| normalize(&name))
+        .transpose()
+}
+
+/// Resolves the document name of a y-websocket request, which always names
+/// one document. Besides the usual forms the whole path is taken as the name,
+/// as the y-websocket server does.
+pub(crate) fn y_websocket_document_name(uri: &Uri) -> Result<String, Error> {
+    match document_name(uri) {
+        Ok(Some(name)) => Ok(name),
+        Ok(None) => Err(Error::Missing),
+        Err(Error::NotFound(_)) => normalize(&percent_decode(uri.path(), false)?),
+        Err(err) => Err(err),
+    }
+}
+
+/// Reads the first query parameter named `key`, percent-decoded.
+pub(crate) fn query_param(uri: &Uri, key: &str) -> Result<Option<String>, Error> {
+    let query = uri.query().unwrap_or_default();
+    for pair in query.split('&') {
+        let (name, value) = pair.split_once('=').unwrap_or((pair, ""));
+        if percent_decode(name, true)? == key {
+            return percent_decode(value, true).map(Some);
+        }
+    }
+
+    Ok(None)
+}
+
+/// Checks the document name carried by a protocol frame, which must already
+/// be in its normalized form.
+pub(crate) fn validate_document_name(name: &str) -> Result<(), Error> {
+    if normalize(name)? != name {
+        return Err(Error::Malformed("not normalized".into()));
+    }
+
+    Ok(())
+}
+
+fn normalize(name: &str) -> Result<String, Error> {
+    let mut segments = Vec::new();
+    for segment in name.trim().split('/') {
+        match segment {
+            "" => continue,
+            "." | ".." => {
+                return Err(Error::Malformed(format!(
+                    "relative segment `{segment}` is not allowed"
+                )));
+            }
+            segment => segments.push(segment),
+        }
+    }
+
+    let name = segments.join("/");
+    if name.is_empty() {
+        return Err(Error::Missing);
+    }
+    if name.len() > MAX_DOCUMENT_NAME_LEN {
+        return Err(Error::Malformed(format!(
+            "exceeded the maximum length, limit: {MAX_DOCUMENT_NAME_LEN}"
+        )));
+    }
+    if name.chars().any(char::is_control) {
+        return Err(Error::Malformed("control character is not allowed".into()));
+    }
+
+    Ok(name)
+}
+
+fn percent_decode(value: &str, query: bool) -> Result<String, Error> {
+    let mut bytes = Vec::with_capacity(value.len());
+
+    let mut iter = value.bytes();
+    while let Some(byte) = iter.next() {
+        match byte {
+            b'%' => {
+                let hex = [iter.next(), iter.next()];
+                // `from_str_radix` alone would take a sign, as in `%+1`
+                let decoded = match hex {
+                    [Some(hi), Some(lo)] if hi.is_ascii_hexdigit() && lo.is_ascii_hexdigit() => {
+                        std::str::from_utf8(&[hi, lo])
+                            .ok()
+                            .and_then(|hex| u8::from_str_radix(hex, 16).ok())
+                    }
+                    _ => None,
+                };
+
+                match decoded {
+                    Some(decoded) => bytes.push(decoded),
+                    None => return Err(Error::Malformed("invalid percent encoding".into())),
+                }
+            }
+            b'+' if query => bytes.push(b' '),
+            byte => bytes.push(byte),
+        }
+    }
+
+    String::from_utf8(bytes).map_err(|_| Error::Malformed("invalid utf-8".into()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn name(uri: &str) -> Result<Option<String>, Error> {
+        document_name(&uri.parse().unwrap())
+    }
+
+    #[test]
+    fn name_from_path() {
+        assert_eq!(name("/doc/notes/a").unwrap().as_deref(), Some("notes/a"));
+        assert_eq!(name("/doc//notes//a/").unwrap().as_deref(), Some("notes/a"));
+        assert_eq!(name("/doc/a%2Fb%20c").unwrap().as_deref(), Some("a/b c"));
+        // `+` is a space only in the query
+        assert_eq!(name("/doc/a+b").unwrap().as_deref(), Some("a+b"));
+    }
+
+    #[test]
+    fn name_from_query() {
+        assert_eq!(name("/?document=a+b").unwrap().as_deref(), Some("a b"));
+        assert_eq!(
+            name("/doc?document=notes%2Fa").unwrap().as_deref(),
+            Some("notes/a")
+        );
+        assert_eq!(name("/").unwrap(), None);
+        assert_eq!(name("/?token=x").unwrap(), None);
+
+        let err = name("/?document=").unwrap_err();
+        assert!(matches!(err, Error::Missing));
+        assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+    }
+
+    #[test]
+    fn relative_segments() {
+        for uri in [
+            "/doc/a%2F..%2Fb",
+            "/doc/a/./b",
+            "/doc/..",
+            "/?document=a/../b",
+        ] {
+            let err = name(uri).unwrap_err();
+            assert!(matches!(err, Error::Malformed(_)), "{uri}");
+            assert_eq!(err.status(), StatusCode::BAD_REQUEST);
+        }
+    }
+
+    #[test]
+    fn unknown_path() {
+        for uri in ["/docs/x", "/document", "/x/doc/a"] {
+            let err = name(uri).unwrap_err();
+            assert!(matches!(err, Error::NotFound(_)), "{uri}");
+            assert_eq!(err.status(), StatusCode::NOT_FOUND);
+        }
+    }
+
+    #[test]
+    fn invalid_percent_encoding() {
+        for uri in [
+            "/doc/a%2",
+            "/doc/a%zz",
+            "/doc/a%+1",
+            "/doc/a%ff",
+            "/?document=%",
+        ] {
+            assert!(matches!(name(uri), Err(Error::Malformed(_))), "{uri}");
+        }
+    }
+
+    #[test]
+    fn control_characters() {
+        assert!(matches!(name("/doc/a%00b"), Err(Error::Malformed(_))));
+        assert!(matches!(name("/doc/a%0Ab"), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn name_length() {
+        let max = "a".repeat(MAX_DOCUMENT_NAME_LEN);
+        assert_eq!(name(&format!("/doc/{max}")).unwrap(), Some(max.clone()));
+        assert!(matches!(
+            name(&format!("/doc/{max}a")),
+            Err(Error::Malformed(_))
+        ));
+    }
+
+    #[test]
+    fn y_websocket_whole_path() {
+        let name = |uri: &str| y_websocket_document_name(&uri.parse().unwrap());
+
+        assert_eq!(name("/doc/a").unwrap(), "a");
+        assert_eq!(name("/rooms/a").unwrap(), "rooms/a");
+        assert!(matches!(name("/"), Err(Error::Missing)));
+        assert!(matches!(name("/a%2F..%2Fb"), Err(Error::Malformed(_))));
+    }
+
+    #[test]
+    fn validate_frame_name() {
+        assert!(validate_document_name("notes/a").is_ok());
+        assert!(validate_document_name("/notes/a").is_err());
+        assert!(validate_document_name("notes/../a").is_err());
+    }
+}
@@ -1,70 +1,200 @@
 use core::panic;
-use std::{cell::RefCell, collections::HashMap, pin::Pin, sync::Arc};
+use std::{cell::RefCell, collections::HashMap, pin::Pin, sync::Arc, time::SystemTime};
 
+use futures::future::join_all;
 use tokio::{
     net::{TcpListener, TcpStream},
     sync::{
         mpsc::{unbounded_channel, UnboundedSender},
         RwLock,
     },
+    time::Instant,
 };
 use tokio_tungstenite::{
     accept_hdr_async_with_config,
-    tungstenite::{protocol::WebSocketConfig, Message},
+    tungstenite::{
+        self,
+        handshake::server::{ErrorResponse, Request, Response},
+        http::{header::SEC_WEBSOCKET_PROTOCOL, HeaderValue, StatusCode},
+        protocol::{frame::coding::CloseCode, CloseFrame},
+        Message,
+    },
 };
-use y_octo::Doc;
+use y_octo::JwstCodecResult;
 
 use crate::{
-    connection::{self, Connection},
-    room::{Room, RoomCommand},
+    audit::AuditSink,
+    auth::{self, AuthRequest, Authentication, Authenticator, Permission},
+    config::ServerConfig,
+    connection::{self, Connection, Expiry},
+    extension::{AuthenticatePayload, ConnectPayload, Extension, Extensions, Reject, RequestInfo},
+    protocol::{
+        document_header, read_auth_token, read_document_name, read_frame, write_authenticated,
+        write_close, write_frame, write_permission_denied, write_token_required, Framing,
+    },
+    room::{Room, RoomCommand, Rooms, ROOM_UNAVAILABLE},
+    route,
+    storage::{DocumentStore, MemoryStore},
     utils::Snowflake,
 };
 
+/// closes a connection which failed to refresh an expired token
+const AUTHENTICATION_EXPIRED: u16 = 4401;
+/// refuses a refreshed token of another user than the one attached
+const USER_MISMATCH: &str = "user_mismatch";
+/// the subprotocol selecting the y-websocket framing on any listener
+const Y_WEBSOCKET_PROTOCOL: &str = "y-websocket";
+
 pub struct Server {
+    config: ServerConfig,
+    store: Arc<dyn DocumentStore>,
+    extensions: Arc<Extensions>,
+    authenticator: Option<Arc<dyn Authenticator>>,
+    audit: Option<Arc<dyn AuditSink>>,
+
     connection_id_generator: RefCell<Snowflake>,
-    rooms: Arc<RwLock<HashMap<String, RoomCommand>>>,
+    rooms: Rooms,
 }
 
 impl Server {
-    pub fn new(machine_id: u64) -> Self {
-        let connection_id_generator = match Snowflake::new(machine_id) {
+    pub fn new(config: ServerConfig) -> Self {
+        let connection_id_generator = match Snowflake::new(config.machine_id) {
             Ok(connection_id_generator) => RefCell::new(connection_id_generator),
             Err(err) => {
-                panic!("cannot register connection id generator, err: {err:?}");
+                panic!("cannot register connection id generator, err: {err}");
             }
         };
 
         Self {
+            config,
+            store: Arc::new(MemoryStore::new()),
+            extensions: Arc::new(Extensions::default()),
+            authenticator: None,
+            audit: None,
+
             connection_id_generator,
             rooms: Arc::new(RwLock::new(HashMap::new())),
         }
     }
 
+    /// Replaces the document store, documents are kept in memory by default.
+    pub fn with_store<S: DocumentStore>(mut self, store: S) -> Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    /// Registers an extension, extensions run in the order of registration.
+    pub fn with_extension<E: Extension>(mut self, extension: E) -> Self {
+        Arc::make_mut(&mut self.extensions).register(extension);
+        self
+    }
+
+    /// Records every update applied to a document in the audit sink.
+    pub fn with_audit_sink<S: AuditSink>(mut self, sink: S) -> Self {
+        self.audit = Some(Arc::new(sink));
+        self
+    }
+
+    /// Requires every connection to authenticate a document before using it,
+    /// documents are open to anyone by default.
+    pub fn with_authenticator<A: Authenticator>(mut self, authenticator: A) -> Self {
+        self.authenticator = Some(Arc::new(authenticator));
+        self
+    }
+
     pub async fn run(self: Pin<&'static Self>) {
-        let listener = match TcpListener::bind("127.0.0.1:2976").await {
-            Ok(listener) => listener,
-            Err(err) => {
-                log::error!("bind tcp listener failed,err: {err}");
-                return;
+        let binds = self
+            .config
+            .bind
+            .iter()
+            .map(|addr| (addr, false))
+            .chain(self.config.y_websocket_bind.iter().map(|addr| (addr, true)));
+
+        let mut listeners = Vec::new();
+        for (addr, y_websocket) in binds {
+            match TcpListener::bind(addr).await {
+                Ok(listener) => listeners.push((listener, y_websocket)),
+                Err(err) => {
+                    log::error!("bind tcp listener `{addr}` failed, err: {err}");
+                    return;
+                }
             }
-        };
+        }
 
+        join_all(
+            listeners
+                .into_iter()
+                .map(|(listener, y_websocket)| self.accept(listener, y_websocket)),
+        )
+        .await;
+    }
+
+    async fn accept(self: Pin<&'static Self>, listener: TcpListener, y_websocket: bool) {
         while let Ok((stream, _)) = listener.accept().await {
-            tokio::spawn(self.handle_stream(stream));
+            tokio::spawn(self.handle_stream(stream, y_websocket));
         }
     }
 
-    async fn handle_stream(self: Pin<&Self>, stream: TcpStream) {
-        // TODO hardcode
-        let room_name = "default";
+    // `ErrorResponse` is dictated by the handshake callback of tungstenite
+    #[allow(clippy::result_large_err)]
+    async fn handle_stream(self: Pin<&Self>, stream: TcpStream, mut y_websocket: bool) {
+        let connection_id = match self.connection_id_generator.borrow_mut().gen() {
+            Ok(connection_id) => connection_id,
+            Err(err) => {
+                log::error!("generate connection id failed, err: {err}");
+                return;
+            }
+        };
+
+        let mut room_name = None;
+        let mut request = None;
+        let mut handshake_auth = None;
 
         let stream = match accept_hdr_async_with_config(
             stream,
-            |_res: &_, resp| {
-                // TODO get document name
+            |req: &Request, mut resp: Response| {
+                if offers_y_websocket(req) {
+                    y_websocket = true;
+                    resp.headers_mut().insert(
+                        SEC_WEBSOCKET_PROTOCOL,
+                        HeaderValue::from_static(Y_WEBSOCKET_PROTOCOL),
+                    );
+                }
+
+                // y-websocket frames cannot name their document
+                room_name = if y_websocket {
+                    route::y_websocket_document_name(req.uri()).map(Some)
+                } else {
+                    route::document_name(req.uri())
+                }
+                .map_err(reject)?;
+                let info = RequestInfo {
+                    uri: req.uri().clone(),
+                    headers: req.headers().clone(),
+                };
+
+                // a token of the request is checked before accepting the
+                // websocket, so that an invalid one fails with a 401
+                if let (Some(authenticator), Some(room_name), Some(token)) =
+                    (&self.authenticator, &room_name, auth::request_token(&info))
+                {
+                    let auth_request = AuthRequest {
+                        connection_id,
+                        document_name: room_name,
+                        token: &token,
+                        request: &info,
+                    };
+                    handshake_auth = authenticator
+                        .authenticate_handshake(auth_request)
+                        .transpose()
+                        .map_err(unauthorized)?
+                        .map(|authentication| (token, authentication));
+                }
+                request = Some(info);
+
                 Ok(resp)
             },
-            Some(WebSocketConfig::default()),
+            Some(self.config.websocket_config()),
         )
         .await
         {
@@ -75,77 +205,439 @@ impl Server {
             }
         };
 
-        let connection_id = match self.connection_id_generator.borrow_mut().gen() {
-            Ok(connection_id) => connection_id,
+        let request = match request {
+            Some(request) => Arc::new(request),
+            None => return,
+        };
+
+        let framing = match (&room_name, y_websocket) {
+            (Some(room_name), true) => Framing::YWebsocket(room_name.clone()),
+            _ => Framing::Hocuspocus,
+        };
+        let conn = Connection::new(connection_id, request.clone(), framing, stream);
+        connection::connection(conn, async {
+            let payload = ConnectPayload {
+                connection_id,
+                request: &request,
+            };
+            if let Err(reject) = self.extensions.on_connect(payload).await {
+                log::warn!("reject connection, reason: {reject}");
+                self.close_stream(CloseCode::Policy, reject.reason()).await;
+                return;
+            }
+
+            if let Some(room_name) = room_name {
+                if self.authenticator.is_none() {
+                    if let Err(reject) = self.attach(&room_name, None, None).await {
+                        self.send_close(&room_name, reject.reason()).await;
+                    }
+                } else if let Some((token, authentication)) = handshake_auth {
+                    self.authenticated(&room_name, &token, Ok(Some(authentication)))
+                        .await;
+                } else if let Some(token) = auth::request_token(&request) {
+                    self.authenticate(&room_name, &token).await;
+                } else if y_websocket {
+                    // a y-websocket client cannot send an `Auth` message, so the
+                    // authenticator decides without a token
+                    self.authenticate(&room_name, "").await;
+                }
+                // otherwise the document waits for its `Auth` message
+            }
+
+            self.handle_conn().await;
+        })
+        .await;
+    }
+
+    async fn handle_conn(self: Pin<&Self>) {
+        let mut closing = false;
+
+        loop {
+            tokio::select! {
+                msg = connection::recv_stream() => match msg {
+                    Some(Ok(Message::Binary(payload))) => {
+                        if !closing {
+                            self.dispatch(payload).await;
+                        }
+                    }
+                    // pong replies are queued by tungstenite itself
+                    Some(Ok(Message::Ping(_) | Message::Pong(_))) => {}
+                    Some(Ok(Message::Text(_) | Message::Frame(_))) => {
+                        log::warn!("unsupported message, ignored");
+                    }
+                    // keep polling so that tungstenite flushes the close reply
+                    Some(Ok(Message::Close(_))) => closing = true,
+                    None => break,
+                    Some(Err(err)) => {
+                        log::error!("read stream failed, err: {err}");
+                        break;
+                    }
+                },
+                (room_name, msg) = connection::recv_room(), if !closing => match msg {
+                    Some(Message::Close(frame)) => {
+                        let reason = frame.map(|frame| frame.reason.into_owned());
+                        self.close_room(&room_name, reason.as_deref().unwrap_or("provider_initiated"))
+                            .await;
+                    }
+                    Some(msg) => {
+                        if let Err(err) = self.send_stream(msg).await {
+                            log::error!("write stream failed, err: {err}");
+                            break;
+                        }
+                    }
+                    None => self.close_room(&room_name, "room_destroyed").await,
+                },
+                (room_name, expiry) = connection::next_expiry(self.config.auth.refresh_margin), if !closing => match expiry {
+                    Expiry::Refresh => {
+                        connection::request_refresh(&room_name);
+                        self.send_message(&room_name, write_token_required()).await;
+                    }
+                    Expiry::Expired => {
+                        log::warn!("token of document `{room_name}` expired, close connection");
+                        self.close_stream(CloseCode::Library(AUTHENTICATION_EXPIRED), "authentication_expired")
+                            .await;
+                        closing = true;
+                    }
+                },
+            }
+        }
+
+        connection::detach_all_rooms();
+    }
+
+    /// Forwards the frame to the room named in its header, attaching the
+    /// connection to that room first when needed.
+    async fn dispatch(self: Pin<&Self>, payload: Vec<u8>) {
+        let payload = match read_frame(&connection::framing(), payload) {
+            Ok(payload) => payload,
             Err(err) => {
-                log::error!("generate connection id failed, err: {err:?}");
+                log::warn!("read frame failed, err: {err}");
                 return;
             }
         };
 
-        let (room_outgoing, room_incoming) = unbounded_channel::<Message>();
-        let room_command = match self
-            .enter_room(connection_id, room_outgoing, room_name)
-            .await
-        {
-            Ok(room_command) => room_command,
+        let room_name = match read_document_name(&payload) {
+            Ok(room_name) => room_name,
             Err(err) => {
-                log::error!("cannot get or create doc: {err:?}");
+                log::warn!("read document name failed, err: {err}");
                 return;
             }
         };
 
-        let conn = Connection::new(
-            connection_id,
+        let token = match read_auth_token(&payload) {
+            Ok(token) => token,
+            Err(err) => {
+                log::warn!("read auth message failed, err: {err}");
+                return;
+            }
+        };
+
+        let attached = connection::is_attached(&room_name);
+        if let Some(token) = token {
+            if attached || route::validate_document_name(&room_name).is_ok() {
+                self.authenticate(&room_name, &token).await;
+            }
+            return;
+        }
+
+        if !attached {
+            if let Err(err) = route::validate_document_name(&room_name) {
+                log::warn!("reject document `{room_name}`, err: {err}");
+                return;
+            }
+            if self.authenticator.is_some() {
+                log::warn!("document `{room_name}` not authenticated, message dropped");
+                return;
+            }
+
+            if let Err(reject) = self.attach(&room_name, None, None).await {
+                self.send_close(&room_name, reject.reason()).await;
+                return;
+            }
+        }
+
+        if connection::send_room(&room_name, payload).is_err() {
+            self.close_room(&room_name, "room_destroyed").await;
+        }
+    }
+
+    /// Checks the token of the document and attaches the room, or renews the
+    /// token of an attached room. The client is told whether it has been
+    /// authenticated, a y-websocket stream is closed when it has not.
+    async fn authenticate(self: Pin<&Self>, room_name: &str, token: &str) {
+        let connection_id = connection::connection_id();
+        let request = connection::request();
+
+        let mut result = Ok(None);
+        if let Some(authenticator) = &self.authenticator {
+            let auth_request = AuthRequest {
+                connection_id,
+                document_name: room_name,
+                token,
+                request: &request,
+            };
+            result = authenticator.authenticate(auth_request).await.map(Some);
+        }
+
+        self.authenticated(room_name, token, result).await;
+    }
+
+    /// Attaches the room or renews its token once the token has been checked.
+    async fn authenticated(
+        self: Pin<&Self>,
+        room_name: &str,
+        token: &str,
+        result: Result<Option<Authentication>, Reject>,
+    ) {
+        let result = match result {
+            Ok(authentication) if connection::is_attached(room_name) => {
+                let user_id = authentication
+                    .as_ref()
+                    .and_then(|authentication| authentication.user_id.clone());
+                let permission = authentication
+                    .as_ref()
+                    .map(|authentication| authentication.permission)
+                    .unwrap_or_default();
+                let expires_at = authentication.as_ref().and_then(expires_at);
+                // the room keeps the user of the first token, e.g. for the audit
+                if connection::room_user_id(room_name) != user_id {
+                    Err(Reject::new(USER_MISMATCH))
+                } else {
+                    self.on_authenticate(room_name, Some(token), authentication.as_ref())
+                        .await
+                        .and_then(|()| {
+                            connection::renew_room(room_name, permission, expires_at)
+                                .map_err(|()| Reject::new(ROOM_UNAVAILABLE))
+                        })
+                        .map(|()| authentication)
+                }
+            }
+            Ok(authentication) => self
+                .attach(room_name, Some(token), authentication.as_ref())
+                .await
+                .map(|()| authentication),
+            Err(reject) => Err(reject),
+        };
+
+        let reply = match &result {
+            Ok(authentication) => write_authenticated(
+                authentication
+                    .as_ref()
+                    .map(|authentication| authentication.permission)
+                    .unwrap_or_default()
+                    .scope(),
+            ),
+            Err(reject) => {
+                log::warn!("authenticate document `{room_name}` failed, reason: {reject}");
+                write_permission_denied(reject.reason())
+            }
+        };
+        self.send_message(room_name, reply).await;
+
+        // a y-websocket connection has no other document to serve
+        if let Err(reject) = result {
+            if matches!(connection::framing(), Framing::YWebsocket(_)) {
+                self.send_close(room_name, reject.reason()).await;
+            }
+        }
+    }
+
+    async fn attach(
+        self: Pin<&Self>,
+        room_name: &str,
+        token: Option<&str>,
+        authentication: Option<&Authentication>,
+    ) -> Result<(), Reject> {
+        let connection_id = connection::connection_id();
+        self.on_authenticate(room_name, token, authentication)
+            .await?;
+
+        let (room_outgoing, room_incoming) = unbounded_channel::<Message>();
+        let permission = authentication
+            .map(|authentication| authentication.permission)
+            .unwrap_or_default();
+        let user_id = authentication.and_then(|authentication| authentication.user_id.clone());
+        let room_command = self
+            .enter_room(
+                connection_id,
+                room_outgoing,
+                permission,
+                user_id.clone(),
+                room_name,
+            )
+            .await
+            .inspect_err(|reject| log::error!("cannot get or create doc: {reject}"))?;
+
+        connection::attach_room(
             room_name.to_owned(),
             room_command,
             room_incoming,
-            stream,
+            user_id,
+            authentication.and_then(expires_at),
         );
-        connection::connection(conn, async {
-            self.handle_conn().await;
-        });
+
+        Ok(())
     }
 
-    async fn handle_conn(self: Pin<&Self>) {}
+    /// Asks the extensions whether the connection may open the document with
+    /// the token, as well as with every refreshed one.
+    async fn on_authenticate(
+        self: Pin<&Self>,
+        room_name: &str,
+        token: Option<&str>,
+        authentication: Option<&Authentication>,
+    ) -> Result<(), Reject> {
+        let request = connection::request();
+        let payload = AuthenticatePayload {
+            connection_id: connection::connection_id(),
+            document_name: room_name,
+            token,
+            authentication,
+            request: &request,
+        };
+
+        self.extensions
+            .on_authenticate(payload)
+            .await
+            .inspect_err(|reject| {
+                log::warn!("reject document `{room_name}`, reason: {reject}");
+            })
+    }
+
+    /// Detaches the room and tells the client that the document is closed,
+    /// the websocket itself stays open for the other documents.
+    async fn close_room(self: Pin<&Self>, room_name: &str, reason: &str) {
+        connection::detach_room(room_name);
+        self.send_close(room_name, reason).await;
+    }
+
+    async fn send_close(self: Pin<&Self>, room_name: &str, reason: &str) {
+        // a y-websocket connection serves a single document
+        if matches!(connection::framing(), Framing::YWebsocket(_)) {
+            self.close_stream(CloseCode::Policy, reason).await;
+            return;
+        }
+
+        self.send_message(room_name, write_close(reason)).await;
+    }
+
+    async fn send_message(self: Pin<&Self>, room_name: &str, body: JwstCodecResult<Vec<u8>>) {
+        let msg = match (document_header(room_name), body) {
+            (Ok(head), Ok(close)) => [head, close].concat(),
+            (Err(err), _) | (_, Err(err)) => {
+                log::error!("write message failed, err: {err}");
+                return;
+            }
+        };
+        if let Err(err) = self.send_stream(Message::Binary(msg)).await {
+            log::error!("write stream failed, err: {err}");
+        }
+    }
+
+    /// Writes a message of a document in the framing of the connection.
+    async fn send_stream(self: Pin<&Self>, msg: Message) -> Result<(), tungstenite::Error> {
+        let msg = match msg {
+            Message::Binary(msg) => match write_frame(&connection::framing(), msg) {
+                Ok(Some(frame)) => Message::Binary(frame),
+                Ok(None) => return Ok(()),
+                Err(err) => {
+                    log::error!("write frame failed, err: {err}");
+                    return Ok(());
+                }
+            },
+            msg => msg,
+        };
+
+        connection::send_stream(msg).await
+    }
+
+    async fn close_stream(self: Pin<&Self>, code: CloseCode, reason: &str) {
+        let frame = CloseFrame {
+            code,
+            reason: reason.to_owned().into(),
+        };
+        if let Err(err) = connection::send_stream(Message::Close(Some(frame))).await {
+            log::error!("close stream failed, err: {err}");
+        }
+    }
 
     async fn enter_room(
         self: Pin<&Self>,
         connection_id: u64,
         room_outgoing: UnboundedSender<Message>,
+        permission: Permission,
+        user_id: Option<String>,
         doc_name: &str,
-    ) -> Result<RoomCommand, ()> {
+    ) -> Result<RoomCommand, Reject> {
         if let Some(room_command) = self.rooms.read().await.get(doc_name) {
-            if let Err(err) = room_command.join(connection_id, room_outgoing) {
+            if let Err(err) = room_command.join(connection_id, room_outgoing, permission, user_id) {
                 log::error!("cannot join room, err: {err:?}");
-                return Err(());
+                return Err(Reject::new(ROOM_UNAVAILABLE));
             }
 
             return Ok(room_command.clone());
         }
 
-        // TODO read doc
-        let doc = Doc::default();
-
+        // the room loads the document itself, joins and messages sent meanwhile
+        // are queued by the room
         let mut rooms = self.rooms.write().await;
-        if !rooms.contains_key(doc_name) {
-            // TODO doc
-            let room_command = Room::create(doc_name.to_owned(), doc, |_doc| {
-                // TODO
-            });
-            rooms.insert(doc_name.to_owned(), room_command);
-        }
-
-        let room_command = rooms.get(doc_name).ok_or(())?;
-        if let Err(err) = room_command.join(connection_id, room_outgoing) {
+        let room_command = rooms.entry(doc_name.to_owned()).or_insert_with(|| {
+            Room::create(
+                doc_name.to_owned(),
+                self.config.room.clone(),
+                self.store.clone(),
+                self.extensions.clone(),
+                self.audit.clone(),
+                self.rooms.clone(),
+            )
+        });
+        if let Err(err) = room_command.join(connection_id, room_outgoing, permission, user_id) {
             log::error!("cannot join room, err: {err:?}");
-            return Err(());
+            return Err(Reject::new(ROOM_UNAVAILABLE));
         }
 
         Ok(room_command.clone())
     }
 }
 
+/// Converts the expiry of the token into a deadline of the runtime.
+fn expires_at(authentication: &Authentication) -> Option<Instant> {
+    let expires_at = authentication.expires_at?;
+    let remaining = expires_at
+        .duration_since(SystemTime::now())
+        .unwrap_or_default();
+
+    Some(Instant::now() + remaining)
+}
+
+/// Whether the client offers the y-websocket subprotocol.
+fn offers_y_websocket(req: &Request) -> bool {
+    req.headers()
+        .get_all(SEC_WEBSOCKET_PROTOCOL)
+        .iter()
+        .filter_map(|value| value.to_str().ok())
+        .flat_map(|value| value.split(','))
+        .any(|protocol| protocol.trim() == Y_WEBSOCKET_PROTOCOL)
+}
+
+fn reject(err: route::Error) -> ErrorResponse {
+    log::warn!("reject websocket request, err: {err}");
+
+    let mut resp = ErrorResponse::new(Some(err.to_string()));
+    *resp.status_mut() = err.status();
+
+    resp
+}
+
+fn unauthorized(reject: Reject) -> ErrorResponse {
+    log::warn!("reject websocket request, reason: {reject}");
+
+    let mut resp = ErrorResponse::new(Some(reject.reason().to_owned()));
+    *resp.status_mut() = StatusCode::UNAUTHORIZED;
+
+    resp
+}
+
 unsafe impl Send for Server {}
 
 unsafe impl Sync for Server {}
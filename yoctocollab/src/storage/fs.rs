@@ -0,0 +1,506 @@
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::{self, ErrorKind, Read, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use sha1::{Digest, Sha1};
+use tokio::{sync::Mutex as AsyncMutex, task::spawn_blocking};
+use y_octo::{Doc, StateVector};
+
+use super::{DocumentStore, Error};
+
+const NAME_FILE: &str = "name";
+const SNAPSHOT_FILE: &str = "snapshot";
+const SNAPSHOT_TMP_FILE: &str = "snapshot.tmp";
+const LOG_FILE: &str = "updates.log";
+const RECORD_HEADER_LEN: usize = 8;
+const DEFAULT_COMPACT_THRESHOLD: u64 = 1 << 20;
+
+/// When appended updates are flushed to the disk.
+#[derive(Debug, Clone, Copy)]
+pub enum FsyncPolicy {
+    /// Fsync after every appended update.
+    Always,
+    /// Fsync an append when the previous fsync of the document is older than
+    /// the interval, snapshots are always synced.
+    Interval(Duration),
+    /// Leave the flushing to the operating system.
+    Never,
+}
+
+#[derive(Default)]
+struct LogState {
+    len: u64,
+    synced_at: Option<Instant>,
+}
+
+/// Stores every document in its own directory, holding a snapshot produced by
+/// `Doc::encode_state_as_update_v1` and an append-only log of the updates
+/// applied after it.
+///
+/// The directory is named by the SHA-1 of the document name, which fits any
+/// file system whatever the name, and keeps the name itself in a `name` file.
+///
+/// Each log record is `[len: u32 le][crc32: u32 le][update]`, a torn record
+/// left by a crash is dropped while loading. The log is compacted into the
+/// snapshot once it exceeds the compact threshold.
+pub struct FsStore {
+    root: PathBuf,
+    fsync: FsyncPolicy,
+    compact_threshold: u64,
+
+    locks: Mutex<HashMap<String, Arc<AsyncMutex<LogState>>>>,
+}
+
+impl FsStore {
+    pub fn new<P: Into<PathBuf>>(root: P) -> Result<Self, Error> {
+        let root = root.into();
+        fs::create_dir_all(&root).map_err(|err| {
+            Error::Io(format!(
+                "create store directory `{}` failed, err: {err}",
+                root.display()
+            ))
+        })?;
+
+        Ok(Self {
+            root,
+            fsync: FsyncPolicy::Always,
+            compact_threshold: DEFAULT_COMPACT_THRESHOLD,
+
+            locks: Mutex::new(HashMap::new()),
+        })
+    }
+
+    pub fn fsync(mut self, fsync: FsyncPolicy) -> Self {
+        self.fsync = fsync;
+        self
+    }
+
+    /// Compacts the log of a document into its snapshot once the log grows
+    /// beyond `compact_threshold` bytes.
+    pub fn compact_threshold(mut self, compact_threshold: u64) -> Self {
+        self.compact_threshold = compact_threshold;
+        self
+    }
+
+    fn document_lock(&self, name: &str) -> Result<Arc<AsyncMutex<LogState>>, Error> {
+        let mut locks = self
+            .locks
+            .lock()
+            .map_err(|err| Error::Backend(format!("fs store poisoned, err: {err}")))?;
+
+        Ok(locks.entry(name.to_owned()).or_default().clone())
+    }
+
+    fn document_dir(&self, name: &str) -> Result<PathBuf, Error> {
+        // an empty name must never resolve to the store root
+        if name.is_empty() {
+            return Err(Error::Backend("empty document name".into()));
+        }
+
+        Ok(self.root.join(hash_name(name)))
+    }
+
+    async fn load_inline(&self, name: &str) -> Result<Vec<Vec<u8>>, Error> {
+        let lock = self.document_lock(name)?;
+        let mut state = lock.lock().await;
+
+        let dir = self.document_dir(name)?;
+        let name = name.to_owned();
+        let (updates, len) = blocking(move || recover(&dir, &name)).await?;
+        state.len = len;
+
+        Ok(updates)
+    }
+
+    async fn store_inline(&self, name: &str, snapshot: Vec<u8>) -> Result<(), Error> {
+        let lock = self.document_lock(name)?;
+        let mut state = lock.lock().await;
+
+        let dir = self.document_dir(name)?;
+        let name = name.to_owned();
+        blocking(move || write_snapshot(&dir, &name, &snapshot)).await?;
+        state.len = 0;
+        state.synced_at = Some(Instant::now());
+
+        Ok(())
+    }
+
+    async fn store_update_inline(&self, name: &str, update: Vec<u8>) -> Result<(), Error> {
+        let lock = self.document_lock(name)?;
+        let mut state = lock.lock().await;
+
+        let sync = match self.fsync {
+            FsyncPolicy::Always => true,
+            FsyncPolicy::Interval(interval) => state
+                .synced_at
+                .is_none_or(|synced_at| synced_at.elapsed() >= interval),
+            FsyncPolicy::Never => false,
+        };
+
+        let dir = self.document_dir(name)?;
+        let owned_name = name.to_owned();
+        let len = blocking(move || append_record(&dir, &owned_name, &update, sync)).await?;
+        state.len = len;
+        if sync {
+            state.synced_at = Some(Instant::now());
+        }
+
+        if state.len > self.compact_threshold {
+            let dir = self.document_dir(name)?;
+            let name = name.to_owned();
+            blocking(move || compact(&dir, &name)).await?;
+            state.len = 0;
+            state.synced_at = Some(Instant::now());
+        }
+
+        Ok(())
+    }
+
+    async fn delete_inline(&self, name: &str) -> Result<(), Error> {
+        let lock = self.document_lock(name)?;
+        let mut state = lock.lock().await;
+
+        let dir = self.document_dir(name)?;
+        let name = name.to_owned();
+        blocking(move || {
+            check_name(&dir, &name)?;
+            match fs::remove_dir_all(&dir) {
+                Err(err) if err.kind() != ErrorKind::NotFound => Err(err),
+                _ => Ok(()),
+            }
+        })
+        .await?;
+        *state = LogState::default();
+
+        Ok(())
+    }
+}
+
+impl DocumentStore for FsStore {
+    fn load<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Vec<u8>>, Error>> {
+        self.load_inline(name).boxed()
+    }
+
+    fn store<'a>(&'a self, name: &'a str, state: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        self.store_inline(name, state.to_owned()).boxed()
+    }
+
+    fn store_update<'a>(
+        &'a self,
+        name: &'a str,
+        update: &'a [u8],
+        _editor: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.store_update_inline(name, update.to_owned()).boxed()
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        self.delete_inline(name).boxed()
+    }
+}
+
+async fn blocking<T: Send + 'static>(
+    f: impl FnOnce() -> io::Result<T> + Send + 'static,
+) -> Result<T, Error> {
+    spawn_blocking(f)
+        .await
+        .map_err(|err| Error::Backend(format!("fs store task failed, err: {err}")))?
+        .map_err(|err| Error::Io(err.to_string()))
+}
+
+/// Reads the snapshot followed by the intact log records, a torn tail is
+/// truncated so that later appends start from a valid record boundary.
+fn recover(dir: &Path, name: &str) -> io::Result<(Vec<Vec<u8>>, u64)> {
+    check_name(dir, name)?;
+
+    let mut updates = Vec::new();
+    match fs::read(dir.join(SNAPSHOT_FILE)) {
+        Ok(snapshot) => updates.push(snapshot),
+        Err(err) if err.kind() == ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+
+    let mut log = match OpenOptions::new()
+        .read(true)
+        .write(true)
+        .open(dir.join(LOG_FILE))
+    {
+        Ok(log) => log,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok((updates, 0)),
+        Err(err) => return Err(err),
+    };
+
+    let mut buffer = Vec::new();
+    log.read_to_end(&mut buffer)?;
+
+    let mut offset = 0;
+    while let Some(update) = read_record(&buffer[offset..]) {
+        updates.push(update.to_owned());
+        offset += RECORD_HEADER_LEN + update.len();
+    }
+
+    if offset != buffer.len() {
+        log::warn!(
+            "drop {} bytes of torn update log `{}`",
+            buffer.len() - offset,
+            dir.display()
+        );
+        log.set_len(offset as u64)?;
+        log.sync_all()?;
+    }
+
+    Ok((updates, offset as u64))
+}
+
+fn read_record(buffer: &[u8]) -> Option<&[u8]> {
+    let header = buffer.get(..RECORD_HEADER_LEN)?;
+    let len = u32::from_le_bytes(header[..4].try_into().ok()?) as usize;
+    let crc = u32::from_le_bytes(header[4..].try_into().ok()?);
+
+    let update = buffer.get(RECORD_HEADER_LEN..RECORD_HEADER_LEN + len)?;
+    (crc32(update) == crc).then_some(update)
+}
+
+fn append_record(dir: &Path, name: &str, update: &[u8], sync: bool) -> io::Result<u64> {
+    let len = u32::try_from(update.len())
+        .map_err(|_| io::Error::new(ErrorKind::InvalidInput, "update too large"))?;
+
+    create_dir(dir, name)?;
+    let mut log = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(dir.join(LOG_FILE))?;
+
+    let mut record = Vec::with_capacity(RECORD_HEADER_LEN + update.len());
+    record.extend_from_slice(&len.to_le_bytes());
+    record.extend_from_slice(&crc32(update).to_le_bytes());
+    record.extend_from_slice(update);
+    log.write_all(&record)?;
+
+    if sync {
+        log.sync_data()?;
+    }
+
+    log.metadata().map(|metadata| metadata.len())
+}
+
+fn compact(dir: &Path, name: &str) -> io::Result<()> {
+    let (updates, _) = recover(dir, name)?;
+
+    let mut doc = Doc::default();
+    for update in updates {
+        doc.apply_update_from_binary(update)
+            .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+    }
+    let snapshot = doc
+        .encode_state_as_update_v1(&StateVector::default())
+        .map_err(|err| io::Error::new(ErrorKind::InvalidData, err))?;
+
+    write_snapshot(dir, name, &snapshot)
+}
+
+/// Atomically replaces the snapshot and then empties the log, replaying a log
+/// left behind by a crash in between is harmless as updates are idempotent.
+fn write_snapshot(dir: &Path, name: &str, snapshot: &[u8]) -> io::Result<()> {
+    create_dir(dir, name)?;
+
+    let tmp = dir.join(SNAPSHOT_TMP_FILE);
+    let mut file = File::create(&tmp)?;
+    file.write_all(snapshot)?;
+    file.sync_all()?;
+    fs::rename(&tmp, dir.join(SNAPSHOT_FILE))?;
+    File::open(dir)?.sync_all()?;
+
+    match OpenOptions::new().write(true).open(dir.join(LOG_FILE)) {
+        Ok(log) => {
+            log.set_len(0)?;
+            log.sync_all()
+        }
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+/// Creates the directory of the document together with its `name` file.
+fn create_dir(dir: &Path, name: &str) -> io::Result<()> {
+    if dir.exists() {
+        return check_name(dir, name);
+    }
+
+    fs::create_dir_all(dir)?;
+    let mut file = File::create(dir.join(NAME_FILE))?;
+    file.write_all(name.as_bytes())?;
+    file.sync_all()
+}
+
+/// Refuses a directory holding another document, which shares the hash.
+fn check_name(dir: &Path, name: &str) -> io::Result<()> {
+    match fs::read(dir.join(NAME_FILE)) {
+        Ok(stored) if stored == name.as_bytes() => Ok(()),
+        Ok(_) => Err(io::Error::new(
+            ErrorKind::InvalidData,
+            format!(
+                "directory `{}` holds another document than `{name}`",
+                dir.display()
+            ),
+        )),
+        Err(err) if err.kind() == ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err),
+    }
+}
+
+fn hash_name(name: &str) -> String {
+    Sha1::digest(name.as_bytes())
+        .iter()
+        .map(|byte| format!("{byte:02x}"))
+        .collect()
+}
+
+fn crc32(buffer: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for byte in buffer {
+        crc ^= *byte as u32;
+        for _ in 0..8 {
+            crc = (crc >> 1) ^ (0xEDB8_8320 & (!(crc & 1)).wrapping_add(1));
+        }
+    }
+
+    !crc
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// A document directory under the system temp directory, removed on drop.
+    struct TempDir(PathBuf);
+
+    impl TempDir {
+        fn new(name: &str) -> Self {
+            let dir =
+                std::env::temp_dir().join(format!("yoctocollab-fs-{name}-{}", std::process::id()));
+            let _ = fs::remove_dir_all(&dir);
+            Self(dir)
+        }
+    }
+
+    impl Drop for TempDir {
+        fn drop(&mut self) {
+            let _ = fs::remove_dir_all(&self.0);
+        }
+    }
+
+    /// The incremental updates of a text typed one chunk after another.
+    fn updates(chunks: &[&str]) -> Vec<Vec<u8>> {
+        let doc = Doc::default();
+        let mut text = doc.get_or_create_text("t").unwrap();
+
+        let mut updates = Vec::new();
+        for chunk in chunks {
+            let state = doc.get_state_vector();
+            text.insert(text.len(), chunk).unwrap();
+            updates.push(doc.encode_state_as_update_v1(&state).unwrap());
+        }
+
+        updates
+    }
+
+    fn read_text(updates: Vec<Vec<u8>>) -> String {
+        let mut doc = Doc::default();
+        for update in updates {
+            doc.apply_update_from_binary(update).unwrap();
+        }
+
+        doc.get_or_create_text("t").unwrap().to_string()
+    }
+
+    #[test]
+    fn crc32_check_value() {
+        assert_eq!(crc32(b"123456789"), 0xCBF4_3926);
+        assert_eq!(crc32(b""), 0);
+    }
+
+    #[test]
+    fn recover_torn_record() {
+        let dir = TempDir::new("torn");
+        let updates = updates(&["hello", " world", "!"]);
+
+        append_record(&dir.0, "doc", &updates[0], false).unwrap();
+        let len = append_record(&dir.0, "doc", &updates[1], false).unwrap();
+
+        // a record cut short by a crash
+        let mut log = OpenOptions::new()
+            .append(true)
+            .open(dir.0.join(LOG_FILE))
+            .unwrap();
+        log.write_all(&(updates[2].len() as u32).to_le_bytes())
+            .unwrap();
+        log.write_all(&crc32(&updates[2]).to_le_bytes()).unwrap();
+        log.write_all(&updates[2][..1]).unwrap();
+
+        let (recovered, recovered_len) = recover(&dir.0, "doc").unwrap();
+        assert_eq!(recovered, updates[..2]);
+        assert_eq!(recovered_len, len);
+
+        append_record(&dir.0, "doc", &updates[2], false).unwrap();
+        let (recovered, _) = recover(&dir.0, "doc").unwrap();
+        assert_eq!(read_text(recovered), "hello world!");
+    }
+
+    #[test]
+    fn recover_corrupted_record() {
+        let dir = TempDir::new("corrupted");
+        let updates = updates(&["hello", " world", "!"]);
+
+        let len = append_record(&dir.0, "doc", &updates[0], false).unwrap();
+        append_record(&dir.0, "doc", &updates[1], false).unwrap();
+
+        let mut buffer = fs::read(dir.0.join(LOG_FILE)).unwrap();
+        *buffer.last_mut().unwrap() ^= 0xff;
+        fs::write(dir.0.join(LOG_FILE), buffer).unwrap();
+
+        let (recovered, recovered_len) = recover(&dir.0, "doc").unwrap();
+        assert_eq!(recovered, updates[..1]);
+        assert_eq!(recovered_len, len);
+
+        append_record(&dir.0, "doc", &updates[1], false).unwrap();
+        append_record(&dir.0, "doc", &updates[2], false).unwrap();
+        let (recovered, _) = recover(&dir.0, "doc").unwrap();
+        assert_eq!(read_text(recovered), "hello world!");
+    }
+
+    #[test]
+    fn compact_and_recover() {
+        let dir = TempDir::new("compact");
+        for update in updates(&["hello", " world"]) {
+            append_record(&dir.0, "doc", &update, false).unwrap();
+        }
+
+        compact(&dir.0, "doc").unwrap();
+        assert_eq!(fs::metadata(dir.0.join(LOG_FILE)).unwrap().len(), 0);
+
+        let (recovered, len) = recover(&dir.0, "doc").unwrap();
+        assert_eq!(recovered.len(), 1);
+        assert_eq!(len, 0);
+        assert_eq!(read_text(recovered), "hello world");
+    }
+
+    #[test]
+    fn check_hash_collision() {
+        let dir = TempDir::new("collision");
+        let updates = updates(&["hello"]);
+        append_record(&dir.0, "doc", &updates[0], false).unwrap();
+
+        // another document whose name would hash to the same directory
+        assert!(append_record(&dir.0, "other", &updates[0], false).is_err());
+        assert!(write_snapshot(&dir.0, "other", &updates[0]).is_err());
+        assert!(recover(&dir.0, "other").is_err());
+        assert_eq!(recover(&dir.0, "doc").unwrap().0, updates);
+    }
+}
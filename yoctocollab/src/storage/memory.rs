@@ -0,0 +1,70 @@
+use std::{collections::HashMap, sync::Mutex};
+
+use futures::{future::BoxFuture, FutureExt};
+
+use super::{DocumentStore, Error};
+
+/// Keeps documents in process memory, they are lost when the server exits.
+#[derive(Default)]
+pub struct MemoryStore {
+    documents: Mutex<HashMap<String, Vec<Vec<u8>>>>,
+}
+
+impl MemoryStore {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn with_documents<T>(
+        &self,
+        f: impl FnOnce(&mut HashMap<String, Vec<Vec<u8>>>) -> T,
+    ) -> Result<T, Error> {
+        let mut documents = self
+            .documents
+            .lock()
+            .map_err(|err| Error::Backend(format!("memory store poisoned, err: {err}")))?;
+
+        Ok(f(&mut documents))
+    }
+}
+
+impl DocumentStore for MemoryStore {
+    fn load<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Vec<u8>>, Error>> {
+        let result =
+            self.with_documents(|documents| documents.get(name).cloned().unwrap_or_default());
+
+        async move { result }.boxed()
+    }
+
+    fn store<'a>(&'a self, name: &'a str, state: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        let result = self.with_documents(|documents| {
+            documents.insert(name.to_owned(), vec![state.to_owned()]);
+        });
+
+        async move { result }.boxed()
+    }
+
+    fn store_update<'a>(
+        &'a self,
+        name: &'a str,
+        update: &'a [u8],
+        _editor: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        let result = self.with_documents(|documents| {
+            documents
+                .entry(name.to_owned())
+                .or_default()
+                .push(update.to_owned());
+        });
+
+        async move { result }.boxed()
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        let result = self.with_documents(|documents| {
+            documents.remove(name);
+        });
+
+        async move { result }.boxed()
+    }
+}
@@ -0,0 +1,52 @@
+mod fs;
+mod memory;
+#[cfg(feature = "sqlite")]
+mod sqlite;
+
+use std::fmt;
+
+use futures::future::BoxFuture;
+
+pub use fs::{FsStore, FsyncPolicy};
+pub use memory::MemoryStore;
+#[cfg(feature = "sqlite")]
+pub use sqlite::{DocumentMetadata, SqliteStore};
+
+#[derive(Debug)]
+pub enum Error {
+    Io(String),
+    Codec(String),
+    Backend(String),
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(msg) | Self::Codec(msg) | Self::Backend(msg) => f.write_str(msg),
+        }
+    }
+}
+
+/// Persists documents across room lifetimes.
+///
+/// Every payload is a Yjs update encoded with v1, a document is restored by
+/// applying the loaded updates in order.
+pub trait DocumentStore: Send + Sync + 'static {
+    /// Loads the updates of the document, empty when the document is unknown.
+    fn load<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Vec<u8>>, Error>>;
+
+    /// Replaces everything stored for the document with its full state.
+    fn store<'a>(&'a self, name: &'a str, state: &'a [u8]) -> BoxFuture<'a, Result<(), Error>>;
+
+    /// Appends an incremental update holding the updates applied to the
+    /// document since it was last stored, `editor` is the user who sent the
+    /// latest of them, or its connection id when it has no user.
+    fn store_update<'a>(
+        &'a self,
+        name: &'a str,
+        update: &'a [u8],
+        editor: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>>;
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), Error>>;
+}
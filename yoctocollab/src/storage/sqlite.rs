@@ -0,0 +1,221 @@
+use std::{
+    path::Path,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use futures::{future::BoxFuture, FutureExt};
+use rusqlite::{params, Connection, OptionalExtension};
+
+use crate::utils::SqliteConnection;
+
+use super::{DocumentStore, Error};
+
+const SCHEMA: &str = "
+CREATE TABLE IF NOT EXISTS documents (
+    name TEXT PRIMARY KEY,
+    created_at INTEGER NOT NULL,
+    updated_at INTEGER NOT NULL,
+    size INTEGER NOT NULL,
+    last_editor TEXT
+);
+CREATE TABLE IF NOT EXISTS snapshots (
+    name TEXT PRIMARY KEY,
+    data BLOB NOT NULL
+);
+CREATE TABLE IF NOT EXISTS updates (
+    id INTEGER PRIMARY KEY AUTOINCREMENT,
+    name TEXT NOT NULL,
+    data BLOB NOT NULL,
+    editor TEXT,
+    created_at INTEGER NOT NULL
+);
+CREATE INDEX IF NOT EXISTS updates_name ON updates (name, id);
+";
+
+#[derive(Debug, Clone)]
+pub struct DocumentMetadata {
+    /// milliseconds since the unix epoch
+    pub created_at: i64,
+    /// milliseconds since the unix epoch
+    pub updated_at: i64,
+    /// bytes of the snapshot and the incremental updates
+    pub size: i64,
+    /// the user id of the last editor, or its connection id without a user
+    pub last_editor: Option<String>,
+}
+
+/// Stores documents in a single SQLite database: the `snapshots` table keeps
+/// the full state, the `updates` table the updates applied after it and the
+/// `documents` table the metadata of every document.
+#[derive(Clone)]
+pub struct SqliteStore {
+    conn: SqliteConnection,
+}
+
+impl SqliteStore {
+    pub fn open<P: AsRef<Path>>(path: P) -> Result<Self, Error> {
+        SqliteConnection::open("sqlite store", path, SCHEMA)
+            .map(|conn| Self { conn })
+            .map_err(Error::Backend)
+    }
+
+    pub fn open_in_memory() -> Result<Self, Error> {
+        SqliteConnection::open_in_memory("sqlite store", SCHEMA)
+            .map(|conn| Self { conn })
+            .map_err(Error::Backend)
+    }
+
+    pub async fn metadata(&self, name: &str) -> Result<Option<DocumentMetadata>, Error> {
+        let name = name.to_owned();
+
+        self.blocking(move |conn| {
+            conn.query_row(
+                "SELECT created_at, updated_at, size, last_editor FROM documents WHERE name = ?1",
+                params![name],
+                |row| {
+                    Ok(DocumentMetadata {
+                        created_at: row.get(0)?,
+                        updated_at: row.get(1)?,
+                        size: row.get(2)?,
+                        last_editor: row.get(3)?,
+                    })
+                },
+            )
+            .optional()
+        })
+        .await
+    }
+
+    async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, Error> {
+        self.conn.blocking(f).await.map_err(Error::Backend)
+    }
+
+    async fn load_inline(&self, name: &str) -> Result<Vec<Vec<u8>>, Error> {
+        let name = name.to_owned();
+
+        self.blocking(move |conn| {
+            let mut updates = Vec::new();
+
+            let snapshot = conn
+                .query_row(
+                    "SELECT data FROM snapshots WHERE name = ?1",
+                    params![name],
+                    |row| row.get(0),
+                )
+                .optional()?;
+            updates.extend(snapshot);
+
+            let mut stmt = conn.prepare("SELECT data FROM updates WHERE name = ?1 ORDER BY id")?;
+            for update in stmt.query_map(params![name], |row| row.get(0))? {
+                updates.push(update?);
+            }
+
+            Ok(updates)
+        })
+        .await
+    }
+
+    async fn store_inline(&self, name: &str, state: Vec<u8>) -> Result<(), Error> {
+        let name = name.to_owned();
+
+        self.blocking(move |conn| {
+            let now = now();
+            let tx = conn.transaction()?;
+
+            tx.execute(
+                "INSERT INTO snapshots (name, data) VALUES (?1, ?2)
+                 ON CONFLICT (name) DO UPDATE SET data = excluded.data",
+                params![name, state],
+            )?;
+            tx.execute("DELETE FROM updates WHERE name = ?1", params![name])?;
+            tx.execute(
+                "INSERT INTO documents (name, created_at, updated_at, size) VALUES (?1, ?2, ?2, ?3)
+                 ON CONFLICT (name) DO UPDATE SET updated_at = excluded.updated_at, size = excluded.size",
+                params![name, now, state.len() as i64],
+            )?;
+
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn store_update_inline(
+        &self,
+        name: &str,
+        update: Vec<u8>,
+        editor: &str,
+    ) -> Result<(), Error> {
+        let name = name.to_owned();
+        let editor = editor.to_owned();
+
+        self.blocking(move |conn| {
+            let now = now();
+            let tx = conn.transaction()?;
+
+            tx.execute(
+                "INSERT INTO updates (name, data, editor, created_at) VALUES (?1, ?2, ?3, ?4)",
+                params![name, update, editor, now],
+            )?;
+            tx.execute(
+                "INSERT INTO documents (name, created_at, updated_at, size, last_editor) VALUES (?1, ?2, ?2, ?3, ?4)
+                 ON CONFLICT (name) DO UPDATE SET
+                     updated_at = excluded.updated_at,
+                     size = size + excluded.size,
+                     last_editor = excluded.last_editor",
+                params![name, now, update.len() as i64, editor],
+            )?;
+
+            tx.commit()
+        })
+        .await
+    }
+
+    async fn delete_inline(&self, name: &str) -> Result<(), Error> {
+        let name = name.to_owned();
+
+        self.blocking(move |conn| {
+            let tx = conn.transaction()?;
+
+            tx.execute("DELETE FROM updates WHERE name = ?1", params![name])?;
+            tx.execute("DELETE FROM snapshots WHERE name = ?1", params![name])?;
+            tx.execute("DELETE FROM documents WHERE name = ?1", params![name])?;
+
+            tx.commit()
+        })
+        .await
+    }
+}
+
+impl DocumentStore for SqliteStore {
+    fn load<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<Vec<Vec<u8>>, Error>> {
+        self.load_inline(name).boxed()
+    }
+
+    fn store<'a>(&'a self, name: &'a str, state: &'a [u8]) -> BoxFuture<'a, Result<(), Error>> {
+        self.store_inline(name, state.to_owned()).boxed()
+    }
+
+    fn store_update<'a>(
+        &'a self,
+        name: &'a str,
+        update: &'a [u8],
+        editor: &'a str,
+    ) -> BoxFuture<'a, Result<(), Error>> {
+        self.store_update_inline(name, update.to_owned(), editor)
+            .boxed()
+    }
+
+    fn delete<'a>(&'a self, name: &'a str) -> BoxFuture<'a, Result<(), Error>> {
+        self.delete_inline(name).boxed()
+    }
+}
+
+fn now() -> i64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|now| now.as_millis() as i64)
+        .unwrap_or_default()
+}
@@ -1,3 +1,7 @@
 mod snowflake;
+#[cfg(feature = "sqlite")]
+mod sqlite;
 
 pub(crate) use snowflake::Snowflake;
+#[cfg(feature = "sqlite")]
+pub(crate) use sqlite::SqliteConnection;
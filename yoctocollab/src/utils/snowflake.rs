@@ -1,5 +1,5 @@
 use std::{
-    ptr,
+    fmt, ptr,
     sync::atomic::{AtomicU64, Ordering},
 };
 
@@ -9,6 +9,14 @@ pub enum Error {
     LibcFailed(String),
 }
 
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::ExceededMaximumLimit(msg) | Self::LibcFailed(msg) => f.write_str(msg),
+        }
+    }
+}
+
 const DEFAULT_EPOCH: u64 = 1685290942000;
 const DEFAULT_MACHINE_BITS: usize = 10;
 const DEFAULT_SEQ_BITS: usize = 12;
@@ -0,0 +1,70 @@
+use std::{
+    path::Path,
+    sync::{Arc, Mutex},
+};
+
+use rusqlite::Connection;
+use tokio::task::spawn_blocking;
+
+/// A SQLite connection shared by the store and the audit sink, the queries
+/// run on blocking tasks one at a time. Errors are messages naming `owner`,
+/// wrapped into the error of the owner.
+#[derive(Clone)]
+pub(crate) struct SqliteConnection {
+    owner: &'static str,
+    conn: Arc<Mutex<Connection>>,
+}
+
+impl SqliteConnection {
+    /// Opens the database in WAL mode and creates the schema.
+    pub(crate) fn open<P: AsRef<Path>>(
+        owner: &'static str,
+        path: P,
+        schema: &str,
+    ) -> Result<Self, String> {
+        let conn = Connection::open(path).map_err(failed)?;
+        conn.pragma_update(None, "journal_mode", "WAL")
+            .map_err(failed)?;
+
+        Self::with_connection(owner, conn, schema)
+    }
+
+    pub(crate) fn open_in_memory(owner: &'static str, schema: &str) -> Result<Self, String> {
+        Self::with_connection(owner, Connection::open_in_memory().map_err(failed)?, schema)
+    }
+
+    fn with_connection(
+        owner: &'static str,
+        conn: Connection,
+        schema: &str,
+    ) -> Result<Self, String> {
+        conn.execute_batch(schema).map_err(failed)?;
+
+        Ok(Self {
+            owner,
+            conn: Arc::new(Mutex::new(conn)),
+        })
+    }
+
+    pub(crate) async fn blocking<T: Send + 'static>(
+        &self,
+        f: impl FnOnce(&mut Connection) -> rusqlite::Result<T> + Send + 'static,
+    ) -> Result<T, String> {
+        let owner = self.owner;
+        let conn = self.conn.clone();
+
+        spawn_blocking(move || {
+            let mut conn = conn
+                .lock()
+                .map_err(|err| format!("{owner} poisoned, err: {err}"))?;
+
+            f(&mut conn).map_err(failed)
+        })
+        .await
+        .map_err(|err| format!("{owner} task failed, err: {err}"))?
+    }
+}
+
+fn failed(err: rusqlite::Error) -> String {
+    format!("sqlite failed, err: {err}")
+}